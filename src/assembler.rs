@@ -0,0 +1,650 @@
+// Two pass assembler for the Intel 8080 mnemonics the disassembler prints.
+// Handles labels, ORG, DB, DW, DS, EQU, END and expressions, and produces a
+// flat binary, so test programs and ROM patches can be written as source.
+//
+// The first pass lays out addresses, which never depend on symbol values
+// because every 8080 instruction has a fixed length. The second encodes.
+use crate::cpu::instruction_length;
+use crate::disassembler::{
+    ALU, ALU_IMMEDIATE, CALLS, JUMPS, PAIRS, REGISTERS, RETURNS, ROTATES, STACK_PAIRS,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+// How deep EQUs can refer to each other before it's taken to be a loop
+const MAX_EQU_DEPTH: usize = 32;
+
+// Mnemonics that aren't in the opcode field tables
+const MNEMONICS: [&str; 30] = [
+    "NOP", "LXI", "DAD", "STAX", "LDAX", "SHLD", "LHLD", "STA", "LDA", "INX", "DCX", "INR", "DCR",
+    "MVI", "HLT", "MOV", "POP", "RET", "PCHL", "SPHL", "JMP", "OUT", "IN", "XTHL", "XCHG", "DI",
+    "EI", "PUSH", "CALL", "RST",
+];
+const DIRECTIVES: [&str; 6] = ["ORG", "DB", "DW", "DS", "EQU", "END"];
+
+#[derive(Debug, Default)]
+pub struct Program {
+    // Address of the first byte, the lowest one assembled. Source starting
+    // with ORG 0 gives an image init_mem can load as it is.
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    // Labels and EQUs with their values, by the names they were given
+    pub symbols: BTreeMap<String, u16>,
+}
+
+// What went wrong and on which line, counting from 1
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// A line of source split into its fields
+struct Statement<'a> {
+    line: usize,
+    label: Option<&'a str>,
+    operation: Option<String>,
+    operands: Vec<&'a str>,
+}
+
+enum Symbol<'a> {
+    Address(u16),
+    // Evaluated when used, so an EQU can refer to labels defined after it.
+    // Keeps the address of its line for `$`.
+    Equ(&'a str, u16),
+}
+
+// Symbols keyed by their upper case name, along with the name as written
+type Symbols<'a> = HashMap<String, (&'a str, Symbol<'a>)>;
+
+pub fn assemble(source: &str) -> Result<Program, Error> {
+    let mut statements = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let statement = parse_line(index + 1, text);
+        if statement.operation.as_deref() == Some("END") {
+            break;
+        }
+        statements.push(statement);
+    }
+
+    // Pass one, define the labels and find where everything goes
+    let mut symbols: Symbols = HashMap::new();
+    let mut addresses = Vec::new();
+    let mut address: u32 = 0;
+    for statement in &statements {
+        let error = |message: String| Error {
+            line: statement.line,
+            message,
+        };
+        let operation = statement.operation.as_deref();
+
+        if operation == Some("ORG") {
+            expect_operands(statement, 1)?;
+            address = evaluate_operand(statement, 0, &symbols, address as u16)? as u16 as u32;
+        }
+        if let Some(label) = statement.label {
+            let symbol = match operation {
+                Some("EQU") => {
+                    expect_operands(statement, 1)?;
+                    Symbol::Equ(statement.operands[0], address as u16)
+                }
+                _ => Symbol::Address(address as u16),
+            };
+            if symbols
+                .insert(label.to_uppercase(), (label, symbol))
+                .is_some()
+            {
+                return Err(error(format!("{} is already defined", label)));
+            }
+        } else if operation == Some("EQU") {
+            return Err(error("EQU needs a label".to_string()));
+        }
+
+        addresses.push(address as u16);
+        address += match operation {
+            None | Some("ORG") | Some("EQU") => 0,
+            Some("DB") => statement
+                .operands
+                .iter()
+                .map(|operand| match string(operand) {
+                    Some(text) => text.len() as u32,
+                    None => 1,
+                })
+                .sum(),
+            Some("DW") => 2 * statement.operands.len() as u32,
+            Some("DS") => {
+                expect_operands(statement, 1)?;
+                evaluate_operand(statement, 0, &symbols, address as u16)? as u16 as u32
+            }
+            Some(mnemonic) => {
+                let (opcode, _) = encode(mnemonic, &statement.operands).map_err(error)?;
+                instruction_length(opcode) as u32
+            }
+        };
+        if address > 0x10000 {
+            return Err(error("past the end of memory".to_string()));
+        }
+    }
+
+    // Pass two, encode with every symbol known
+    let mut memory: BTreeMap<u16, u8> = BTreeMap::new();
+    for (statement, address) in statements.iter().zip(addresses) {
+        let mut bytes = Vec::new();
+        match statement.operation.as_deref() {
+            None | Some("ORG") | Some("DS") => {}
+            // Checked here so a bad EQU is reported on its line
+            Some("EQU") => {
+                evaluate_operand(statement, 0, &symbols, address)?;
+            }
+            Some("DB") => {
+                for (i, operand) in statement.operands.iter().enumerate() {
+                    match string(operand) {
+                        Some(text) => bytes.extend(text.bytes()),
+                        None => bytes.push(byte(statement, i, &symbols, address)?),
+                    }
+                }
+            }
+            Some("DW") => {
+                for i in 0..statement.operands.len() {
+                    let value = word(statement, i, &symbols, address)?;
+                    bytes.extend(&[value as u8, (value >> 8) as u8]);
+                }
+            }
+            Some(mnemonic) => {
+                let (opcode, data) = encode(mnemonic, &statement.operands).unwrap();
+                bytes.push(opcode);
+                if let Some(i) = data {
+                    match instruction_length(opcode) {
+                        2 => bytes.push(byte(statement, i, &symbols, address)?),
+                        _ => {
+                            let value = word(statement, i, &symbols, address)?;
+                            bytes.extend(&[value as u8, (value >> 8) as u8]);
+                        }
+                    }
+                }
+            }
+        }
+
+        for (offset, value) in bytes.into_iter().enumerate() {
+            let at = address.wrapping_add(offset as u16);
+            if memory.insert(at, value).is_some() {
+                return Err(Error {
+                    line: statement.line,
+                    message: format!("${:04x} was already assembled", at),
+                });
+            }
+        }
+    }
+
+    let mut program = Program::default();
+    if let (Some((&first, _)), Some((&last, _))) = (memory.iter().next(), memory.iter().last()) {
+        program.origin = first;
+        program.bytes = vec![0; (last - first) as usize + 1];
+        for (at, value) in memory {
+            program.bytes[(at - first) as usize] = value;
+        }
+    }
+    for (name, symbol) in symbols.values() {
+        let value = match symbol {
+            Symbol::Address(address) => *address,
+            Symbol::Equ(expression, address) => {
+                evaluate(expression, &symbols, *address, 0).expect("checked in pass two") as u16
+            }
+        };
+        program.symbols.insert(name.to_string(), value);
+    }
+    Ok(program)
+}
+
+fn parse_line(line: usize, text: &str) -> Statement<'_> {
+    // Comments start at a ; that isn't quoted
+    let mut quote = None;
+    let mut end = text.len();
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, ';') => {
+                end = i;
+                break;
+            }
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    let text = text[..end].trim_end();
+
+    let mut statement = Statement {
+        line,
+        label: None,
+        operation: None,
+        operands: Vec::new(),
+    };
+    let mut rest = text.trim_start();
+
+    // A label ends with a colon, or starts in the first column without one
+    let first = rest.split_whitespace().next().unwrap_or("");
+    if let Some(colon) = first.find(':') {
+        statement.label = Some(&first[..colon]);
+        rest = rest[colon + 1..].trim_start();
+    } else if !text.starts_with(char::is_whitespace) && !first.is_empty() && !is_operation(first)
+    {
+        statement.label = Some(first);
+        rest = rest[first.len()..].trim_start();
+    }
+
+    if rest.is_empty() {
+        return statement;
+    }
+    let split = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    statement.operation = Some(rest[..split].to_uppercase());
+    let operands = rest[split..].trim();
+    if !operands.is_empty() {
+        statement.operands = split_operands(operands);
+    }
+    statement
+}
+
+pub(crate) fn is_operation(word: &str) -> bool {
+    let word = word.to_uppercase();
+    let tables = [
+        &ALU[..],
+        &ALU_IMMEDIATE[..],
+        &CALLS[..],
+        &JUMPS[..],
+        &RETURNS[..],
+        &ROTATES[..],
+        &MNEMONICS[..],
+        &DIRECTIVES[..],
+    ];
+    tables.iter().any(|table| table.contains(&word.as_str()))
+}
+
+// Splits operands on the commas that aren't inside quotes
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, ',') => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+// The text of a quoted string operand in DB. A single quoted character is
+// left alone so it works in expressions.
+fn string(operand: &str) -> Option<&str> {
+    let quoted = operand.len() >= 2
+        && ((operand.starts_with('"') && operand.ends_with('"'))
+            || (operand.starts_with('\'') && operand.ends_with('\'') && operand.len() != 3));
+    if quoted {
+        Some(&operand[1..operand.len() - 1])
+    } else {
+        None
+    }
+}
+
+fn expect_operands(statement: &Statement, count: usize) -> Result<(), Error> {
+    if statement.operands.len() == count {
+        Ok(())
+    } else {
+        Err(Error {
+            line: statement.line,
+            message: format!(
+                "expected {} operand{}",
+                count,
+                if count == 1 { "" } else { "s" }
+            ),
+        })
+    }
+}
+
+// Picks the opcode for an instruction. Returns it along with the index of the
+// operand holding its immediate data, if it takes any.
+fn encode(mnemonic: &str, operands: &[&str]) -> Result<(u8, Option<usize>), String> {
+    let count = |n: usize| {
+        if operands.len() == n {
+            Ok(())
+        } else {
+            Err(format!("{} takes {} operand{}", mnemonic, n, if n == 1 { "" } else { "s" }))
+        }
+    };
+    let find = |table: &[&str], name: &str| table.iter().position(|entry| *entry == name);
+    let register = |i: usize| {
+        find(&REGISTERS, &operands[i].to_uppercase())
+            .map(|r| r as u8)
+            .ok_or_else(|| format!("{} isn't a register", operands[i]))
+    };
+    let pair = |i: usize, table: &[&str]| {
+        find(table, &operands[i].to_uppercase())
+            .map(|rp| (rp as u8) << 4)
+            .ok_or_else(|| format!("{} isn't a register pair {} can use", operands[i], mnemonic))
+    };
+
+    // Instructions named by the DDD field
+    let tables: [(&[&str], u8, usize); 6] = [
+        (&ALU, 0x80, 1),
+        (&ALU_IMMEDIATE, 0xc6, 1),
+        (&JUMPS, 0xc2, 1),
+        (&CALLS, 0xc4, 1),
+        (&RETURNS, 0xc0, 0),
+        (&ROTATES, 0x07, 0),
+    ];
+    for (table, base, operand_count) in tables.iter() {
+        if let Some(ddd) = find(table, mnemonic) {
+            count(*operand_count)?;
+            let opcode = base | (ddd as u8) << 3;
+            return match *base {
+                // ADD r and friends take a register
+                0x80 => Ok((opcode | register(0)?, None)),
+                0xc0 | 0x07 => Ok((opcode, None)),
+                _ => Ok((opcode, Some(0))),
+            };
+        }
+    }
+
+    let none = |opcode: u8| count(0).map(|_| (opcode, None));
+    match mnemonic {
+        "NOP" => none(0x00),
+        "HLT" => none(0x76),
+        "RET" => none(0xc9),
+        "PCHL" => none(0xe9),
+        "SPHL" => none(0xf9),
+        "XTHL" => none(0xe3),
+        "XCHG" => none(0xeb),
+        "DI" => none(0xf3),
+        "EI" => none(0xfb),
+        "SHLD" | "LHLD" | "STA" | "LDA" | "JMP" | "CALL" | "OUT" | "IN" => {
+            count(1)?;
+            let opcode = match mnemonic {
+                "SHLD" => 0x22,
+                "LHLD" => 0x2a,
+                "STA" => 0x32,
+                "LDA" => 0x3a,
+                "JMP" => 0xc3,
+                "CALL" => 0xcd,
+                "OUT" => 0xd3,
+                _ => 0xdb,
+            };
+            Ok((opcode, Some(0)))
+        }
+        "LXI" => {
+            count(2)?;
+            Ok((0x01 | pair(0, &PAIRS)?, Some(1)))
+        }
+        "DAD" | "INX" | "DCX" => {
+            count(1)?;
+            let base = match mnemonic {
+                "DAD" => 0x09,
+                "INX" => 0x03,
+                _ => 0x0b,
+            };
+            Ok((base | pair(0, &PAIRS)?, None))
+        }
+        "STAX" | "LDAX" => {
+            count(1)?;
+            let base = if mnemonic == "STAX" { 0x02 } else { 0x0a };
+            Ok((base | pair(0, &PAIRS[..2])?, None))
+        }
+        "PUSH" | "POP" => {
+            count(1)?;
+            let base = if mnemonic == "PUSH" { 0xc5 } else { 0xc1 };
+            Ok((base | pair(0, &STACK_PAIRS)?, None))
+        }
+        "INR" | "DCR" => {
+            count(1)?;
+            let base = if mnemonic == "INR" { 0x04 } else { 0x05 };
+            Ok((base | register(0)? << 3, None))
+        }
+        "MVI" => {
+            count(2)?;
+            Ok((0x06 | register(0)? << 3, Some(1)))
+        }
+        "MOV" => {
+            count(2)?;
+            let opcode = 0x40 | register(0)? << 3 | register(1)?;
+            if opcode == 0x76 {
+                return Err("MOV M,M isn't an instruction".to_string());
+            }
+            Ok((opcode, None))
+        }
+        "RST" => {
+            count(1)?;
+            match operands[0].parse::<u8>() {
+                Ok(n) if n < 8 => Ok((0xc7 | n << 3, None)),
+                _ => Err("RST takes 0 to 7".to_string()),
+            }
+        }
+        _ => Err(format!("unknown instruction {}", mnemonic)),
+    }
+}
+
+fn evaluate_operand(
+    statement: &Statement,
+    i: usize,
+    symbols: &Symbols,
+    address: u16,
+) -> Result<i64, Error> {
+    let operand = statement.operands.get(i).ok_or_else(|| Error {
+        line: statement.line,
+        message: "missing operand".to_string(),
+    })?;
+    evaluate(operand, symbols, address, 0).map_err(|message| Error {
+        line: statement.line,
+        message,
+    })
+}
+
+fn byte(statement: &Statement, i: usize, symbols: &Symbols, address: u16) -> Result<u8, Error> {
+    let value = evaluate_operand(statement, i, symbols, address)?;
+    if (-128..=255).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(Error {
+            line: statement.line,
+            message: format!("{} doesn't fit in a byte", value),
+        })
+    }
+}
+
+fn word(statement: &Statement, i: usize, symbols: &Symbols, address: u16) -> Result<u16, Error> {
+    let value = evaluate_operand(statement, i, symbols, address)?;
+    if (-32768..=65535).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(Error {
+            line: statement.line,
+            message: format!("{} doesn't fit in a word", value),
+        })
+    }
+}
+
+// Evaluates an expression. `address` is the value of `$`, the address of the
+// line it's on.
+fn evaluate(text: &str, symbols: &Symbols, address: u16, depth: usize) -> Result<i64, String> {
+    if depth > MAX_EQU_DEPTH {
+        return Err("EQUs refer to each other in a loop".to_string());
+    }
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+        symbols,
+        address,
+        depth,
+    };
+    let value = parser.expression(0)?;
+    parser.skip_spaces();
+    match parser.peek() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected {} in {}", c, text)),
+    }
+}
+
+// Binary operators from loosest to tightest binding
+const PRECEDENCE: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser<'a, 'b> {
+    chars: Vec<char>,
+    position: usize,
+    symbols: &'b Symbols<'a>,
+    address: u16,
+    depth: usize,
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn operator(&mut self, level: usize) -> Option<&'static str> {
+        self.skip_spaces();
+        for operator in PRECEDENCE[level] {
+            let end = self.position + operator.len();
+            if end <= self.chars.len()
+                && self.chars[self.position..end].iter().copied().eq(operator.chars())
+            {
+                self.position = end;
+                return Some(*operator);
+            }
+        }
+        None
+    }
+
+    fn expression(&mut self, level: usize) -> Result<i64, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut value = self.expression(level + 1)?;
+        while let Some(operator) = self.operator(level) {
+            let right = self.expression(level + 1)?;
+            value = match operator {
+                "|" => value | right,
+                "^" => value ^ right,
+                "&" => value & right,
+                "<<" => value.checked_shl(right as u32).unwrap_or(0),
+                ">>" => value.checked_shr(right as u32).unwrap_or(0),
+                "+" => value + right,
+                "-" => value - right,
+                "*" => value * right,
+                _ if right == 0 => return Err("division by zero".to_string()),
+                "/" => value / right,
+                _ => value % right,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        self.skip_spaces();
+        match self.peek() {
+            Some('-') => {
+                self.position += 1;
+                Ok(-self.unary()?)
+            }
+            Some('+') => {
+                self.position += 1;
+                self.unary()
+            }
+            Some('~') => {
+                self.position += 1;
+                Ok(!self.unary()?)
+            }
+            Some('(') => {
+                self.position += 1;
+                let value = self.expression(0)?;
+                self.skip_spaces();
+                if self.peek() != Some(')') {
+                    return Err("missing )".to_string());
+                }
+                self.position += 1;
+                Ok(value)
+            }
+            Some('\'') => {
+                let value = self.chars.get(self.position + 1).copied();
+                match (value, self.chars.get(self.position + 2)) {
+                    (Some(c), Some('\'')) => {
+                        self.position += 3;
+                        Ok(c as i64)
+                    }
+                    _ => Err("a character constant is one character in quotes".to_string()),
+                }
+            }
+            Some(_) => self.primary(),
+            None => Err("missing value".to_string()),
+        }
+    }
+
+    // Numbers, symbols and $
+    fn primary(&mut self) -> Result<i64, String> {
+        let start = self.position;
+        if self.peek() == Some('$') {
+            self.position += 1;
+        }
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+            self.position += 1;
+        }
+        let token: String = self.chars[start..self.position].iter().collect();
+        if token.is_empty() {
+            return Err(format!("unexpected {}", self.peek().unwrap()));
+        }
+        if token == "$" {
+            return Ok(self.address as i64);
+        }
+        if token.starts_with(|c: char| c.is_ascii_digit() || c == '$') {
+            return number(&token).ok_or_else(|| format!("{} isn't a number", token));
+        }
+
+        match self.symbols.get(&token.to_uppercase()) {
+            Some((_, Symbol::Address(address))) => Ok(*address as i64),
+            Some((_, Symbol::Equ(expression, address))) => {
+                evaluate(expression, self.symbols, *address, self.depth + 1)
+            }
+            None => Err(format!("{} isn't defined", token)),
+        }
+    }
+}
+
+// Hex as $ff, 0xff or 0ffh, binary as 1010b, otherwise decimal
+fn number(token: &str) -> Option<i64> {
+    let lower = token.to_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else if lower.ends_with('b') && lower[..lower.len() - 1].chars().all(|c| c == '0' || c == '1')
+    {
+        (&lower[..lower.len() - 1], 2)
+    } else {
+        (&lower[..], 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
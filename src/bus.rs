@@ -0,0 +1,193 @@
+// Memory bus seen by the cpu, every read and write the core makes goes
+// through here so a machine can decide what each address actually does
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+}
+
+// What lives behind a range of addresses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Ram,
+    // Readable, writes are ignored
+    Rom,
+    // Repeats `size` bytes starting at `base`, like the Invaders RAM mirror
+    Mirror { base: u16, size: u16 },
+    // Nothing connected, reads float and writes are ignored
+    Unmapped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MapEntry {
+    pub start: u16,
+    pub end: u16,
+    pub region: Region,
+}
+
+// Describes the address space of a machine. Addresses not covered by any
+// entry behave as plain RAM, so an empty map is a flat 64KB of RAM.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMap {
+    pub entries: Vec<MapEntry>,
+}
+
+// Value returned by reads from unmapped addresses
+pub const OPEN_BUS: u8 = 0xff;
+
+impl MemoryMap {
+    pub fn new() -> MemoryMap {
+        Default::default()
+    }
+
+    // Declares `start..=end` as `region`, later entries win over earlier ones
+    pub fn map(&mut self, start: u16, end: u16, region: Region) -> &mut MemoryMap {
+        self.entries.push(MapEntry { start, end, region });
+        self
+    }
+
+    pub fn rom(&mut self, start: u16, end: u16) -> &mut MemoryMap {
+        self.map(start, end, Region::Rom)
+    }
+
+    pub fn ram(&mut self, start: u16, end: u16) -> &mut MemoryMap {
+        self.map(start, end, Region::Ram)
+    }
+
+    pub fn mirror(&mut self, start: u16, end: u16, base: u16, size: u16) -> &mut MemoryMap {
+        self.map(start, end, Region::Mirror { base, size })
+    }
+
+    pub fn unmapped(&mut self, start: u16, end: u16) -> &mut MemoryMap {
+        self.map(start, end, Region::Unmapped)
+    }
+
+    // Finds the entry an address falls in
+    pub fn entry(&self, address: u16) -> Option<&MapEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.start <= address && address <= entry.end)
+    }
+
+    pub fn region(&self, address: u16) -> Region {
+        self.entry(address)
+            .map(|entry| entry.region)
+            .unwrap_or(Region::Ram)
+    }
+
+    // Resolves mirrors down to the backing address and its region. Returns
+    // None for unmapped addresses (or a mirror that never resolves).
+    pub fn resolve(&self, address: u16) -> Option<(u16, Region)> {
+        let mut address = address;
+        // Bounded so a map with mirrors pointing at each other can't hang
+        for _ in 0..8 {
+            let entry = match self.entry(address) {
+                Some(entry) => *entry,
+                None => return Some((address, Region::Ram)),
+            };
+            match entry.region {
+                Region::Ram | Region::Rom => return Some((address, entry.region)),
+                Region::Unmapped => return None,
+                Region::Mirror { base, size } => {
+                    let start = entry.start;
+                    let offset = address.wrapping_sub(start) % size.max(1);
+                    address = base.wrapping_add(offset);
+                }
+            }
+        }
+        None
+    }
+
+    // Reads a byte out of `memory` the way the hardware would see it
+    pub fn read(&self, memory: &[u8], address: u16) -> u8 {
+        match self.resolve(address) {
+            Some((backing, _)) => memory[backing as usize],
+            None => OPEN_BUS,
+        }
+    }
+
+    // Writes a byte into `memory`, dropping writes to ROM and unmapped space
+    pub fn write(&self, memory: &mut [u8], address: u16, value: u8) {
+        if let Some((backing, Region::Ram)) = self.resolve(address) {
+            memory[backing as usize] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4KB of ROM, 4KB of RAM mirrored above it, then nothing
+    fn map() -> MemoryMap {
+        let mut map = MemoryMap::new();
+        map.rom(0x0000, 0x0fff)
+            .ram(0x1000, 0x1fff)
+            .mirror(0x2000, 0x7fff, 0x1000, 0x1000)
+            .unmapped(0x8000, 0xffff);
+        map
+    }
+
+    #[test]
+    fn empty_map_is_ram() {
+        let map = MemoryMap::new();
+        let mut memory = vec![0; 0x10000];
+        map.write(&mut memory, 0xffff, 0x42);
+        assert_eq!(map.read(&memory, 0xffff), 0x42);
+        assert_eq!(map.region(0x1234), Region::Ram);
+    }
+
+    #[test]
+    fn rom_ignores_writes() {
+        let map = map();
+        let mut memory = vec![0; 0x10000];
+        memory[0x0010] = 0xc3;
+        map.write(&mut memory, 0x0010, 0x00);
+        assert_eq!(map.read(&memory, 0x0010), 0xc3);
+        assert_eq!(map.resolve(0x0fff), Some((0x0fff, Region::Rom)));
+    }
+
+    #[test]
+    fn mirrors_wrap_around() {
+        let map = map();
+        let mut memory = vec![0; 0x10000];
+        map.write(&mut memory, 0x1005, 0x11);
+        // Every 4KB above the RAM lands back on it
+        assert_eq!(map.read(&memory, 0x2005), 0x11);
+        assert_eq!(map.read(&memory, 0x7005), 0x11);
+        map.write(&mut memory, 0x3fff, 0x22);
+        assert_eq!(memory[0x1fff], 0x22);
+        assert_eq!(memory[0x3fff], 0x00);
+        assert_eq!(map.resolve(0x6000), Some((0x1000, Region::Ram)));
+    }
+
+    #[test]
+    fn unmapped_reads_float() {
+        let map = map();
+        let mut memory = vec![0; 0x10000];
+        map.write(&mut memory, 0x8000, 0x33);
+        assert_eq!(memory[0x8000], 0x00);
+        assert_eq!(map.read(&memory, 0x8000), OPEN_BUS);
+        assert_eq!(map.read(&memory, 0xffff), OPEN_BUS);
+        assert_eq!(map.resolve(0x8000), None);
+    }
+
+    #[test]
+    fn later_entries_win() {
+        let mut map = map();
+        map.ram(0x0f00, 0x0fff);
+        let mut memory = vec![0; 0x10000];
+        map.write(&mut memory, 0x0f00, 0x44);
+        assert_eq!(map.read(&memory, 0x0f00), 0x44);
+        assert_eq!(map.region(0x0eff), Region::Rom);
+    }
+
+    #[test]
+    fn mirror_loops_give_up() {
+        let mut map = MemoryMap::new();
+        map.mirror(0x0000, 0x00ff, 0x0100, 0x100)
+            .mirror(0x0100, 0x01ff, 0x0000, 0x100);
+        assert_eq!(map.resolve(0x0010), None);
+        assert_eq!(map.read(&vec![0; 0x10000], 0x0010), OPEN_BUS);
+    }
+}
@@ -1,4 +1,6 @@
 // References - http://www.emulator101.com/emulator-shell.html
+// Flag behaviour from the 8080 Programmers Manual and the 8080/8085
+// Assembly Language Programming manual (AC on logical instructions)
 #[derive(Debug, Default)]
 pub struct ConditionCodes {
     pub z: bool,
@@ -10,54 +12,64 @@ pub struct ConditionCodes {
 }
 
 impl ConditionCodes {
-    pub fn set_add_flags(&mut self, val: u16) {
-        self.set_zero_flag(val);
-        self.set_sign_flag(val);
-        self.set_carry_flag_add(val);
-        let lower = val as u8;
-        self.set_parity_flag(lower);
-        self.set_ac_flag(val);
+    // ADD, ADC, ADI, ACI
+    pub fn set_add_flags(&mut self, a: u8, value: u8, carry: bool) {
+        let result = (a as u16) + (value as u16) + (carry as u16);
+        self.cy = result > 0xff;
+        self.ac = (a & 0xf) + (value & 0xf) + (carry as u8) > 0xf;
+        self.set_zsp_flags(result as u8);
     }
 
-    pub fn set_inr_flags(&mut self, val: u16) {
-        self.set_zero_flag(val);
-        self.set_sign_flag(val);
-        let lower = val as u8;
-        self.set_parity_flag(lower);
-        self.set_ac_flag(val);
+    // SUB, SBB, SUI, SBI, CMP, CPI
+    // The 8080 subtracts by adding the two's complement, so AC is the carry
+    // out of bit 3 of that addition and CY is the inverted carry (a borrow)
+    pub fn set_sub_flags(&mut self, a: u8, value: u8, borrow: bool) {
+        let result = (a as u16)
+            .wrapping_sub(value as u16)
+            .wrapping_sub(borrow as u16);
+        self.cy = result > 0xff;
+        self.ac = (a & 0xf) + (!value & 0xf) + (!borrow as u8) > 0xf;
+        self.set_zsp_flags(result as u8);
     }
 
-    pub fn set_dcr_flags(&mut self, val: u16) {
-        self.set_zero_flag(val);
-        self.set_sign_flag(val);
-        let lower = val as u8;
-        self.set_parity_flag(lower);
-        self.set_ac_flag(val);
+    // INR leaves CY alone, AC is set when the low nibble wraps to 0
+    pub fn set_inr_flags(&mut self, result: u8) {
+        self.ac = result & 0xf == 0;
+        self.set_zsp_flags(result);
     }
 
-    pub fn set_sub_flags(&mut self, val: u16) {
-        self.set_zero_flag(val);
-        self.set_sign_flag(val);
-        self.set_carry_flag_sub(val);
-        let lower = val as u8;
-        self.set_parity_flag(lower);
-        self.set_ac_flag(val);
+    // DCR adds 0xff, so AC is set unless the low nibble borrowed
+    pub fn set_dcr_flags(&mut self, result: u8) {
+        self.ac = result & 0xf != 0xf;
+        self.set_zsp_flags(result);
     }
 
-    pub fn set_zero_flag(&mut self, val: u16) {
-        self.z = val & 0xff == 0;
+    // ANA, ANI clear CY, and set AC to the OR of bit 3 of both operands
+    pub fn set_and_flags(&mut self, a: u8, value: u8) {
+        self.cy = false;
+        self.ac = (a | value) & 0x08 != 0;
+        self.set_zsp_flags(a & value);
     }
 
-    pub fn set_sign_flag(&mut self, val: u16) {
-        self.s = val & 0x80 == 0x80;
+    // XRA, XRI, ORA, ORI clear both CY and AC
+    pub fn set_logic_flags(&mut self, result: u8) {
+        self.cy = false;
+        self.ac = false;
+        self.set_zsp_flags(result);
     }
 
-    pub fn set_carry_flag_add(&mut self, val: u16) {
-        self.cy = val > 0xff;
+    pub fn set_zsp_flags(&mut self, val: u8) {
+        self.set_zero_flag(val);
+        self.set_sign_flag(val);
+        self.set_parity_flag(val);
     }
 
-    pub fn set_carry_flag_sub(&mut self, val: u16) {
-        self.cy = val < 0x100;
+    pub fn set_zero_flag(&mut self, val: u8) {
+        self.z = val == 0;
+    }
+
+    pub fn set_sign_flag(&mut self, val: u8) {
+        self.s = val & 0x80 == 0x80;
     }
 
     pub fn set_parity_flag(&mut self, val: u8) {
@@ -65,7 +77,107 @@ impl ConditionCodes {
         self.p = one_count & 1 == 0;
     }
 
-    pub fn set_ac_flag(&mut self, val: u16) {
-        self.ac = (val as u8) > 0xf;
+    // Condition from the CCC field of a jump, call or return,
+    // NZ Z NC C PO PE P M
+    pub fn check(&self, ccc: u8) -> bool {
+        match ccc {
+            0 => !self.z,
+            1 => self.z,
+            2 => !self.cy,
+            3 => self.cy,
+            4 => !self.p,
+            5 => self.p,
+            6 => !self.s,
+            _ => self.s,
+        }
+    }
+
+    // Flags as the byte PUSH PSW stores, laid out S Z 0 AC 0 P 1 CY
+    pub fn to_psw(&self) -> u8 {
+        (self.s as u8) << 7
+            | (self.z as u8) << 6
+            | (self.ac as u8) << 4
+            | (self.p as u8) << 2
+            | 0x02
+            | (self.cy as u8)
+    }
+
+    // POP PSW, the unused bits are ignored
+    pub fn set_psw(&mut self, psw: u8) {
+        self.s = psw & 0x80 != 0;
+        self.z = psw & 0x40 != 0;
+        self.ac = psw & 0x10 != 0;
+        self.p = psw & 0x04 != 0;
+        self.cy = psw & 0x01 != 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subtraction_borrows() {
+        let mut flags = ConditionCodes::default();
+        // AC is the carry out of bit 3 when adding the complement, so an
+        // equal subtraction sets it and a borrow from the low nibble doesn't
+        flags.set_sub_flags(0x05, 0x05, false);
+        assert!(flags.z && !flags.cy && flags.ac);
+        flags.set_sub_flags(0x05, 0x06, false);
+        assert!(flags.s && flags.cy && !flags.ac);
+        flags.set_sub_flags(0x10, 0x01, false);
+        assert!(!flags.cy && !flags.ac);
+        // SBB takes the borrow in too
+        flags.set_sub_flags(0x05, 0x05, true);
+        assert!(!flags.z && flags.cy && !flags.ac);
+        flags.set_sub_flags(0x06, 0x05, true);
+        assert!(flags.z && !flags.cy && flags.ac);
+    }
+
+    #[test]
+    fn and_sets_ac_from_bit_3() {
+        let mut flags = ConditionCodes {
+            cy: true,
+            ..Default::default()
+        };
+        flags.set_and_flags(0x08, 0x00);
+        assert!(flags.ac && flags.z && !flags.cy);
+        flags.set_and_flags(0x01, 0x08);
+        assert!(flags.ac);
+        flags.set_and_flags(0xf7, 0xf7);
+        assert!(!flags.ac && flags.s);
+    }
+
+    #[test]
+    fn increment_and_decrement_leave_cy() {
+        let mut flags = ConditionCodes {
+            cy: true,
+            ..Default::default()
+        };
+        flags.set_inr_flags(0x10);
+        assert!(flags.ac && flags.cy);
+        flags.set_inr_flags(0x00);
+        assert!(flags.ac && flags.z && flags.cy);
+        flags.set_inr_flags(0x11);
+        assert!(!flags.ac);
+
+        flags.cy = false;
+        flags.set_dcr_flags(0x0f);
+        assert!(!flags.ac && !flags.cy);
+        flags.set_dcr_flags(0x10);
+        assert!(flags.ac && !flags.cy);
+    }
+
+    #[test]
+    fn psw_layout() {
+        let mut flags = ConditionCodes::default();
+        assert_eq!(flags.to_psw(), 0x02);
+        // Bit 1 always reads back set, bits 3 and 5 clear
+        flags.set_psw(0xff);
+        assert_eq!(flags.to_psw(), 0xd7);
+        flags.set_psw(0x00);
+        assert_eq!(flags.to_psw(), 0x02);
+        flags.set_psw(0x10);
+        assert!(flags.ac && !flags.s && !flags.z && !flags.p && !flags.cy);
     }
 }
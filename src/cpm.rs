@@ -0,0 +1,616 @@
+// Minimal CP/M 2.2 machine, runs .COM programs on the core
+// BDOS and BIOS calls are trapped by address and handled on the host,
+// files are read from and written to a host directory
+// References - http://www.gaby.de/cpm/manuals/archive/cpm22htm/ch5.htm
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+// Programs call the BDOS through the jump at address 5 and are loaded at 0x100
+pub const BDOS: u16 = 0x0005;
+pub const TPA: u16 = 0x0100;
+
+// Where the BDOS and BIOS would live, the word at address 6 points at the
+// BDOS so programs use it as the top of their memory
+const BDOS_BASE: u16 = 0xfe00;
+const BIOS_BASE: u16 = 0xff00;
+const BIOS_ENTRIES: u16 = 17;
+
+// Default FCBs and DMA buffer in page zero
+const FCB_1: u16 = 0x005c;
+const FCB_2: u16 = 0x006c;
+const DEFAULT_DMA: u16 = 0x0080;
+
+const RECORD_SIZE: usize = 128;
+// End of file marker for text files
+const CTRL_Z: u8 = 0x1a;
+
+// Console the program talks to through the BDOS and BIOS
+pub trait Console {
+    fn write(&mut self, byte: u8);
+    // Blocks until a character is available
+    fn read(&mut self) -> u8;
+    // True if a character is waiting
+    fn status(&mut self) -> bool;
+}
+
+// The host terminal. Stdin can't be polled without blocking, so status
+// always reports a key and the following read waits for one.
+#[derive(Debug, Default)]
+pub struct StdConsole;
+
+impl Console for StdConsole {
+    fn write(&mut self, byte: u8) {
+        let mut out = io::stdout();
+        out.write_all(&[byte]).unwrap();
+        out.flush().unwrap();
+    }
+
+    fn read(&mut self) -> u8 {
+        let mut byte = [0; 1];
+        match io::stdin().read(&mut byte) {
+            Ok(1) if byte[0] == b'\n' => b'\r',
+            Ok(1) => byte[0],
+            _ => CTRL_Z,
+        }
+    }
+
+    fn status(&mut self) -> bool {
+        true
+    }
+}
+
+// Console backed by buffers, for tests and scripted runs
+#[derive(Debug, Default)]
+pub struct BufferConsole {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl Console for BufferConsole {
+    fn write(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+
+    fn read(&mut self) -> u8 {
+        self.input.pop_front().unwrap_or(CTRL_Z)
+    }
+
+    fn status(&mut self) -> bool {
+        !self.input.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct Cpm<C: Console> {
+    pub cpu: Cpu,
+    pub console: C,
+    // Host directory that stands in for the disk
+    pub root: PathBuf,
+    // Set once the program returns to CP/M
+    pub exited: bool,
+    dma: u16,
+    current_disk: u8,
+    // Open files, keyed by the address of their FCB
+    files: HashMap<u16, File>,
+}
+
+impl<C: Console> Cpm<C> {
+    pub fn new(console: C, root: PathBuf) -> Cpm<C> {
+        let mut cpu = Cpu::new(Vec::new());
+
+        // Warm boot vector and BDOS entry point
+        cpu.state.memory[0x0000] = 0xc3;
+        cpu.state.memory[0x0001] = (BIOS_BASE + 3) as u8;
+        cpu.state.memory[0x0002] = ((BIOS_BASE + 3) >> 8) as u8;
+        cpu.state.memory[BDOS as usize] = 0xc3;
+        cpu.state.memory[BDOS as usize + 1] = BDOS_BASE as u8;
+        cpu.state.memory[BDOS as usize + 2] = (BDOS_BASE >> 8) as u8;
+
+        // Calls are trapped before these run, RETs are only a fallback
+        cpu.state.memory[BDOS_BASE as usize] = 0xc9;
+        for entry in 0..BIOS_ENTRIES {
+            let address = (BIOS_BASE + entry * 3) as usize;
+            cpu.state.memory[address] = 0xc9;
+        }
+
+        // The stack starts with a return to the warm boot, so a program can
+        // finish with a plain RET
+        cpu.state.sp = BDOS_BASE - 2;
+        cpu.state.memory[cpu.state.sp as usize] = 0x00;
+        cpu.state.memory[cpu.state.sp as usize + 1] = 0x00;
+        cpu.state.pc = TPA;
+
+        Cpm {
+            cpu,
+            console,
+            root,
+            exited: false,
+            dma: DEFAULT_DMA,
+            current_disk: 0,
+            files: HashMap::new(),
+        }
+    }
+
+    // Loads a .COM at 0x100 and sets up the command tail and default FCBs
+    // from the program arguments. The program has to fit below the BDOS.
+    pub fn load(&mut self, program: &[u8], args: &[String]) -> Result<(), String> {
+        let start = TPA as usize;
+        let room = (BDOS_BASE - TPA) as usize;
+        if program.len() > room {
+            return Err(format!(
+                "program is {} bytes, only {} fit below the BDOS",
+                program.len(),
+                room
+            ));
+        }
+        self.cpu.state.memory[start..start + program.len()].copy_from_slice(program);
+
+        let tail: String = args.iter().map(|arg| format!(" {}", arg)).collect();
+        let tail = tail.to_uppercase();
+        let tail = &tail.as_bytes()[..tail.len().min(RECORD_SIZE - 1)];
+        let dma = DEFAULT_DMA as usize;
+        self.cpu.state.memory[dma] = tail.len() as u8;
+        self.cpu.state.memory[dma + 1..dma + 1 + tail.len()].copy_from_slice(tail);
+
+        self.fill_fcb(FCB_1, args.first().map(|arg| arg.as_str()).unwrap_or(""));
+        self.fill_fcb(FCB_2, args.get(1).map(|arg| arg.as_str()).unwrap_or(""));
+        Ok(())
+    }
+
+    // Runs until the program exits or the cpu halts
+    pub fn run(&mut self) {
+        while self.step().is_some() {}
+    }
+
+    // Handles a BDOS or BIOS call if the cpu is about to enter one, otherwise
+    // executes a single instruction. Returns None once the program is done.
+    pub fn step(&mut self) -> Option<u32> {
+        if self.exited {
+            return None;
+        }
+
+        let pc = self.cpu.state.pc;
+        // Jumping to 0 is a warm boot, the program is done
+        if pc == 0 {
+            self.exited = true;
+            return None;
+        }
+        if pc == BDOS || pc == BDOS_BASE {
+            self.bdos();
+            self.ret();
+            return Some(10);
+        }
+        if (BIOS_BASE..BIOS_BASE + BIOS_ENTRIES * 3).contains(&pc) {
+            self.bios((pc - BIOS_BASE) / 3);
+            self.ret();
+            return Some(10);
+        }
+
+        // Nothing raises interrupts here, so HLT never ends
+        if self.cpu.halted() {
+            return None;
+        }
+
+        Some(self.cpu.step())
+    }
+
+    // Returns from a trapped call like RET would
+    fn ret(&mut self) {
+        let state = &mut self.cpu.state;
+        let low = state.read(state.sp) as u16;
+        let high = state.read(state.sp.wrapping_add(1)) as u16;
+        state.pc = high << 8 | low;
+        state.sp = state.sp.wrapping_add(2);
+    }
+
+    // Results come back in A and L, with B and H holding the high byte
+    fn set_result(&mut self, value: u16) {
+        let state = &mut self.cpu.state;
+        state.l = value as u8;
+        state.a = value as u8;
+        state.h = (value >> 8) as u8;
+        state.b = (value >> 8) as u8;
+    }
+
+    fn bios(&mut self, entry: u16) {
+        match entry {
+            // BOOT, WBOOT
+            0 | 1 => self.exited = true,
+            // CONST
+            2 => {
+                let ready = self.console.status();
+                self.cpu.state.a = if ready { 0xff } else { 0 };
+            }
+            // CONIN
+            3 => self.cpu.state.a = self.console.read(),
+            // CONOUT
+            4 => self.console.write(self.cpu.state.c),
+            // Disk and printer entries aren't emulated
+            _ => self.cpu.state.a = 0,
+        }
+    }
+
+    fn bdos(&mut self) {
+        let function = self.cpu.state.c;
+        let e = self.cpu.state.e;
+        let de = (self.cpu.state.d as u16) << 8 | e as u16;
+
+        match function {
+            // System reset
+            0 => self.exited = true,
+            // Console input, echoed
+            1 => {
+                let byte = self.console.read();
+                self.console.write(byte);
+                self.set_result(byte as u16);
+            }
+            // Console output
+            2 => self.console.write(e),
+            // Direct console I/O, E = 0xff reads without waiting
+            6 => {
+                if e == 0xff {
+                    let byte = if self.console.status() {
+                        self.console.read()
+                    } else {
+                        0
+                    };
+                    self.set_result(byte as u16);
+                } else {
+                    self.console.write(e);
+                }
+            }
+            // Print string, terminated by a '$'
+            9 => {
+                let mut address = de;
+                loop {
+                    let byte = self.cpu.state.read(address);
+                    if byte == b'$' {
+                        break;
+                    }
+                    self.console.write(byte);
+                    address = address.wrapping_add(1);
+                }
+            }
+            // Read console buffer, DE points at the max length
+            10 => self.read_buffer(de),
+            // Console status
+            11 => {
+                let ready = self.console.status();
+                self.set_result(if ready { 0xff } else { 0 });
+            }
+            // Version number, CP/M 2.2
+            12 => self.set_result(0x0022),
+            // Reset disk system
+            13 => {
+                self.dma = DEFAULT_DMA;
+                self.current_disk = 0;
+                self.set_result(0);
+            }
+            // Select disk
+            14 => {
+                self.current_disk = e;
+                self.set_result(0);
+            }
+            15 => self.open_file(de),
+            16 => self.close_file(de),
+            19 => self.delete_file(de),
+            20 => self.read_sequential(de),
+            21 => self.write_sequential(de),
+            22 => self.make_file(de),
+            23 => self.rename_file(de),
+            // Current disk
+            25 => self.set_result(self.current_disk as u16),
+            // Set DMA address
+            26 => self.dma = de,
+            // Get/set user code, only user 0 exists
+            32 => self.set_result(0),
+            33 => self.read_random(de),
+            34 => self.write_random(de),
+            35 => self.file_size(de),
+            // Set random record from the sequential position
+            36 => {
+                let record = self.sequential_record(de);
+                self.set_random_record(de, record);
+            }
+            _ => self.set_result(0xff),
+        }
+    }
+
+    fn read_buffer(&mut self, buffer: u16) {
+        let max = self.cpu.state.read(buffer);
+        let mut count: u8 = 0;
+        while count < max {
+            let byte = self.console.read();
+            if byte == b'\r' || byte == b'\n' || byte == CTRL_Z {
+                break;
+            }
+            self.console.write(byte);
+            let address = buffer.wrapping_add(2).wrapping_add(count as u16);
+            self.cpu.state.write(address, byte);
+            count += 1;
+        }
+        self.cpu.state.write(buffer.wrapping_add(1), count);
+        self.console.write(b'\r');
+        self.console.write(b'\n');
+    }
+
+    // Read-only host files still open, writes to them then fail
+    fn open_file(&mut self, fcb: u16) {
+        let file = self.find_file(fcb).and_then(|path| {
+            match OpenOptions::new().read(true).write(true).open(&path) {
+                Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                    File::open(path).ok()
+                }
+                file => file.ok(),
+            }
+        });
+        match file {
+            Some(file) => {
+                self.reset_position(fcb);
+                self.files.insert(fcb, file);
+                self.set_result(0);
+            }
+            None => self.set_result(0xff),
+        }
+    }
+
+    fn close_file(&mut self, fcb: u16) {
+        match self.files.remove(&fcb) {
+            Some(_) => self.set_result(0),
+            None => self.set_result(0xff),
+        }
+    }
+
+    fn delete_file(&mut self, fcb: u16) {
+        let deleted = self
+            .find_file(fcb)
+            .map(|path| fs::remove_file(path).is_ok())
+            .unwrap_or(false);
+        self.set_result(if deleted { 0 } else { 0xff });
+    }
+
+    fn make_file(&mut self, fcb: u16) {
+        let name = self.fcb_name(fcb);
+        let path = self.find_file(fcb).unwrap_or_else(|| self.root.join(name));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path);
+        match file {
+            Ok(file) => {
+                self.reset_position(fcb);
+                self.files.insert(fcb, file);
+                self.set_result(0);
+            }
+            Err(_) => self.set_result(0xff),
+        }
+    }
+
+    // The new name is in the second half of the FCB
+    fn rename_file(&mut self, fcb: u16) {
+        let renamed = match self.find_file(fcb) {
+            Some(from) => {
+                let name = self.fcb_name(fcb.wrapping_add(16));
+                fs::rename(from, self.root.join(name)).is_ok()
+            }
+            None => false,
+        };
+        self.set_result(if renamed { 0 } else { 0xff });
+    }
+
+    fn read_sequential(&mut self, fcb: u16) {
+        let record = self.sequential_record(fcb);
+        let result = self.read_record(fcb, record);
+        if result == 0 {
+            self.set_sequential_record(fcb, record + 1);
+        }
+        self.set_result(result as u16);
+    }
+
+    fn write_sequential(&mut self, fcb: u16) {
+        let record = self.sequential_record(fcb);
+        let result = self.write_record(fcb, record);
+        if result == 0 {
+            self.set_sequential_record(fcb, record + 1);
+        }
+        self.set_result(result as u16);
+    }
+
+    // Random access leaves the sequential position on the record it used
+    fn read_random(&mut self, fcb: u16) {
+        let record = self.random_record(fcb);
+        let result = self.read_record(fcb, record);
+        self.set_sequential_record(fcb, record);
+        self.set_result(result as u16);
+    }
+
+    fn write_random(&mut self, fcb: u16) {
+        let record = self.random_record(fcb);
+        let result = self.write_record(fcb, record);
+        self.set_sequential_record(fcb, record);
+        self.set_result(result as u16);
+    }
+
+    fn file_size(&mut self, fcb: u16) {
+        match self.find_file(fcb).and_then(|path| fs::metadata(path).ok()) {
+            Some(metadata) => {
+                let records = (metadata.len() as usize).div_ceil(RECORD_SIZE);
+                self.set_random_record(fcb, records);
+                self.set_result(0);
+            }
+            None => self.set_result(0xff),
+        }
+    }
+
+    // Reads a record into the DMA buffer, a short last record is padded with
+    // ^Z. Returns 1 at end of file, 9 if the file isn't open.
+    fn read_record(&mut self, fcb: u16, record: usize) -> u8 {
+        let file = match self.files.get_mut(&fcb) {
+            Some(file) => file,
+            None => return 9,
+        };
+        let mut buffer = [CTRL_Z; RECORD_SIZE];
+        let mut count = 0;
+        if file.seek(SeekFrom::Start((record * RECORD_SIZE) as u64)).is_ok() {
+            while count < RECORD_SIZE {
+                match file.read(&mut buffer[count..]) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => count += read,
+                }
+            }
+        }
+        if count == 0 {
+            return 1;
+        }
+        for (i, byte) in buffer.iter().enumerate() {
+            let address = self.dma.wrapping_add(i as u16);
+            self.cpu.state.write(address, *byte);
+        }
+        0
+    }
+
+    // Writes the DMA buffer as a record. Returns 2 if the write failed,
+    // 9 if the file isn't open.
+    fn write_record(&mut self, fcb: u16, record: usize) -> u8 {
+        let mut buffer = [0; RECORD_SIZE];
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.cpu.state.read(self.dma.wrapping_add(i as u16));
+        }
+        let file = match self.files.get_mut(&fcb) {
+            Some(file) => file,
+            None => return 9,
+        };
+        let written = file
+            .seek(SeekFrom::Start((record * RECORD_SIZE) as u64))
+            .and_then(|_| file.write_all(&buffer));
+        match written {
+            Ok(_) => 0,
+            Err(_) => 2,
+        }
+    }
+
+    // FCB layout: 0 drive, 1-8 name, 9-11 type, 12 extent, 13-14 reserved,
+    // 15 record count, 16-31 allocation, 32 current record, 33-35 random record
+    fn fcb_byte(&mut self, fcb: u16, offset: u16) -> u8 {
+        self.cpu.state.read(fcb.wrapping_add(offset))
+    }
+
+    fn set_fcb_byte(&mut self, fcb: u16, offset: u16, value: u8) {
+        self.cpu.state.write(fcb.wrapping_add(offset), value);
+    }
+
+    fn reset_position(&mut self, fcb: u16) {
+        self.set_fcb_byte(fcb, 12, 0);
+        self.set_fcb_byte(fcb, 14, 0);
+        self.set_fcb_byte(fcb, 32, 0);
+    }
+
+    // Each extent holds 128 records
+    fn sequential_record(&mut self, fcb: u16) -> usize {
+        let extent = self.fcb_byte(fcb, 12) as usize & 0x1f;
+        let module = self.fcb_byte(fcb, 14) as usize & 0x3f;
+        let current = self.fcb_byte(fcb, 32) as usize & 0x7f;
+        (module * 32 + extent) * 128 + current
+    }
+
+    fn set_sequential_record(&mut self, fcb: u16, record: usize) {
+        self.set_fcb_byte(fcb, 32, (record % 128) as u8);
+        self.set_fcb_byte(fcb, 12, ((record / 128) % 32) as u8);
+        self.set_fcb_byte(fcb, 14, (record / (128 * 32)) as u8);
+    }
+
+    fn random_record(&mut self, fcb: u16) -> usize {
+        let low = self.fcb_byte(fcb, 33) as usize;
+        let high = self.fcb_byte(fcb, 34) as usize;
+        high << 8 | low
+    }
+
+    fn set_random_record(&mut self, fcb: u16, record: usize) {
+        self.set_fcb_byte(fcb, 33, record as u8);
+        self.set_fcb_byte(fcb, 34, (record >> 8) as u8);
+        self.set_fcb_byte(fcb, 35, (record >> 16) as u8);
+    }
+
+    // "NAME.TYP" from the name and type fields, attribute bits stripped
+    fn fcb_name(&mut self, fcb: u16) -> String {
+        let mut name = String::new();
+        let mut extension = String::new();
+        for offset in 1..12 {
+            let byte = self.fcb_byte(fcb, offset) & 0x7f;
+            if byte == b' ' {
+                continue;
+            }
+            if offset < 9 {
+                name.push(byte as char);
+            } else {
+                extension.push(byte as char);
+            }
+        }
+        if extension.is_empty() {
+            name
+        } else {
+            format!("{}.{}", name, extension)
+        }
+    }
+
+    // CP/M names are upper case, match host files regardless of case
+    fn find_file(&mut self, fcb: u16) -> Option<PathBuf> {
+        let name = self.fcb_name(fcb);
+        fs::read_dir(&self.root)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| {
+                path.is_file()
+                    && path
+                        .file_name()
+                        .map(|file| file.to_string_lossy().to_uppercase() == name)
+                        .unwrap_or(false)
+            })
+    }
+
+    // Parses "[D:]NAME[.TYP]" into an FCB, '*' pads the field with '?'
+    fn fill_fcb(&mut self, fcb: u16, arg: &str) {
+        let arg = arg.to_uppercase();
+        let (drive, file) = match arg.find(':') {
+            Some(1) if arg.as_bytes()[0].is_ascii_uppercase() => {
+                (arg.as_bytes()[0] - b'A' + 1, &arg[2..])
+            }
+            _ => (0, &arg[..]),
+        };
+        let (name, extension) = match file.find('.') {
+            Some(dot) => (&file[..dot], &file[dot + 1..]),
+            None => (file, ""),
+        };
+
+        self.set_fcb_byte(fcb, 0, drive);
+        self.fill_fcb_field(fcb, 1, 8, name);
+        self.fill_fcb_field(fcb, 9, 3, extension);
+        for offset in 12..16 {
+            self.set_fcb_byte(fcb, offset, 0);
+        }
+    }
+
+    fn fill_fcb_field(&mut self, fcb: u16, start: u16, length: u16, text: &str) {
+        let mut bytes = text.bytes();
+        let mut wildcard = false;
+        for offset in start..start + length {
+            let byte = match bytes.next() {
+                _ if wildcard => b'?',
+                Some(b'*') => {
+                    wildcard = true;
+                    b'?'
+                }
+                Some(byte) => byte,
+                None => b' ',
+            };
+            self.set_fcb_byte(fcb, offset, byte);
+        }
+    }
+}
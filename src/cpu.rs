@@ -0,0 +1,749 @@
+use crate::bus::Bus;
+use crate::intel8080_state::StateIntel8080;
+use crate::io::IoBus;
+use crate::trace::{Record, Tracer};
+use std::collections::BTreeSet;
+
+// Extra cycles a conditional call or return takes when the condition holds
+pub const CONDITIONAL_CYCLES: u32 = 6;
+
+// Cycles that pass for each step while halted, an interrupt is checked
+// for between each of them
+pub const HALT_CYCLES: u32 = 4;
+
+// Core of the emulator, executes intel 8080 instructions one at a time
+// so it can be driven by any frontend (SDL, test harnesses, headless tools)
+#[derive(Debug, Default)]
+pub struct Cpu {
+    pub state: StateIntel8080,
+    // Devices reached through the IN and OUT instructions
+    pub io: IoBus,
+    // Total cycles executed since the cpu was created
+    pub cycles: u64,
+    // Addresses run_until stops at, before running the instruction there
+    pub breakpoints: BTreeSet<u16>,
+    // Logs each instruction before it runs
+    pub tracer: Option<Tracer>,
+}
+
+impl Cpu {
+    pub fn new(rom: Vec<u8>) -> Cpu {
+        let mut state: StateIntel8080 = Default::default();
+        state.init_mem(&rom);
+        Cpu {
+            state,
+            io: IoBus::new(),
+            cycles: 0,
+            breakpoints: BTreeSet::new(),
+            tracer: None,
+        }
+    }
+
+    // Runs instructions until at least `cycles` cycles have passed, returns the
+    // number of cycles actually executed
+    pub fn run_cycles(&mut self, cycles: u32) -> u32 {
+        let mut cycle_count: u32 = 0;
+        while cycle_count < cycles {
+            cycle_count += self.step();
+        }
+        cycle_count
+    }
+
+    // Runs instructions until the total cycle count reaches `cycle`. Working
+    // from the running total means the few cycles each call overshoots by are
+    // taken out of the next call instead of drifting.
+    // Returns false if it stopped early because PC reached a breakpoint or
+    // an instruction tripped a watchpoint. The first instruction always runs,
+    // so calling it again carries on from one.
+    pub fn run_until(&mut self, cycle: u64) -> bool {
+        while self.cycles < cycle {
+            self.step();
+            if self.breakpoints.contains(&self.state.pc) || self.state.watchpoints.hit.is_some() {
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.stop("breakpoint", self.state.pc);
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    // True after HLT until an interrupt is taken
+    pub fn halted(&self) -> bool {
+        self.state.halted
+    }
+
+    // Executes a single instruction, or a pending interrupt, and returns the
+    // cycles it took. While halted time still passes, HALT_CYCLES at a time.
+    pub fn step(&mut self) -> u32 {
+        let state = &mut self.state;
+        state.watchpoints.hit = None;
+
+        // EI only lets interrupts in once the instruction after it has run
+        let delayed = state.interrupt_delay;
+        state.interrupt_delay = false;
+
+        let (opcode, byte_1, byte_2, interrupt) = match state.acknowledge_interrupt(delayed) {
+            // The instruction comes off the data bus instead of memory. PC is
+            // backed up by its length so executing it leaves PC where the
+            // interrupt found it, which is what RST and CALL push.
+            Some(instruction) => {
+                let length = instruction_length(instruction[0]);
+                state.pc = state.pc.wrapping_sub(length);
+                (instruction[0], instruction[1], instruction[2], true)
+            }
+            // A halted cpu does nothing until an interrupt wakes it
+            None if state.halted => {
+                self.cycles += HALT_CYCLES as u64;
+                return HALT_CYCLES;
+            }
+            // Fetch the opcode and the bytes after it from memory, so code that
+            // lives in RAM runs the same as code in the ROM. Fetches don't
+            // count as reads for the watchpoints.
+            None => (
+                state.peek(state.pc),
+                state.peek(state.pc.wrapping_add(1)),
+                state.peek(state.pc.wrapping_add(2)),
+                false,
+            ),
+        };
+
+        // Taking an interrupt isn't traced, the backed up PC never ran the
+        // instruction. The handler's first instruction is the next record.
+        if let (false, Some(tracer)) = (interrupt, &mut self.tracer) {
+            tracer.record(Record::new(self.cycles, state, [opcode, byte_1, byte_2]));
+        }
+
+        // PC moves past the instruction before it runs, so jumps just replace
+        // it and calls push it as the return address
+        state.pc = state.pc.wrapping_add(instruction_length(opcode));
+
+        // Conditional calls and returns add their extra cycles when taken
+        let cycle_count =
+            get_cycles(opcode) as u32 + execute(state, &mut self.io, opcode, byte_1, byte_2);
+        self.cycles += cycle_count as u64;
+
+        // PC is already past the one byte HLT
+        if let (true, Some(tracer)) = (state.halted, &mut self.tracer) {
+            tracer.stop("HLT", state.pc.wrapping_sub(1));
+        }
+
+        cycle_count
+    }
+}
+
+// Runs one instruction, decoded from the fields of its opcode rather than
+// listed one by one. Bits 7-6 pick the group, bits 5-3 (DDD) are a
+// destination register, condition or RST number, bits 2-0 (SSS) a source
+// register or the operation within the group. Register pairs are DDD >> 1.
+// Every opcode is defined, the undocumented ones alias NOP, JMP, RET and CALL.
+// Returns the cycles taken on top of get_cycles.
+//
+// Heavy use of https://altairclone.com/downloads/manuals/8080%20Programmers%20Manual.pdf
+// and Emulator 101 guide for instruction implementation
+fn execute(state: &mut StateIntel8080, io: &mut IoBus, opcode: u8, byte_1: u8, byte_2: u8) -> u32 {
+    let ddd = (opcode >> 3) & 0x07;
+    let sss = opcode & 0x07;
+    let rp = ddd >> 1;
+    let word = (byte_2 as u16) << 8 | byte_1 as u16;
+
+    match (opcode >> 6, ddd, sss) {
+        // NOP, 0x08 0x10 0x18 0x20 0x28 0x30 0x38 are undocumented aliases
+        (0, _, 0) => {}
+        // LXI rp,word
+        (0, _, 1) if ddd & 1 == 0 => state.set_pair(rp, word),
+        // DAD rp
+        (0, _, 1) => {
+            let (result, carry) = state.pair(HL).overflowing_add(state.pair(rp));
+            state.set_pair(HL, result);
+            state.condition.cy = carry;
+        }
+        // STAX B
+        (0, 0, 2) => state.write(state.pair(BC), state.a),
+        // LDAX B
+        (0, 1, 2) => state.a = state.read(state.pair(BC)),
+        // STAX D
+        (0, 2, 2) => state.write(state.pair(DE), state.a),
+        // LDAX D
+        (0, 3, 2) => state.a = state.read(state.pair(DE)),
+        // SHLD word
+        (0, 4, 2) => {
+            state.write(word, state.l);
+            state.write(word.wrapping_add(1), state.h);
+        }
+        // LHLD word
+        (0, 5, 2) => {
+            state.l = state.read(word);
+            state.h = state.read(word.wrapping_add(1));
+        }
+        // STA word
+        (0, 6, 2) => state.write(word, state.a),
+        // LDA word
+        (0, 7, 2) => state.a = state.read(word),
+        // INX rp
+        (0, _, 3) if ddd & 1 == 0 => state.set_pair(rp, state.pair(rp).wrapping_add(1)),
+        // DCX rp
+        (0, _, 3) => state.set_pair(rp, state.pair(rp).wrapping_sub(1)),
+        // INR r
+        (0, _, 4) => {
+            let result = state.reg(ddd).wrapping_add(1);
+            state.condition.set_inr_flags(result);
+            state.set_reg(ddd, result);
+        }
+        // DCR r
+        (0, _, 5) => {
+            let result = state.reg(ddd).wrapping_sub(1);
+            state.condition.set_dcr_flags(result);
+            state.set_reg(ddd, result);
+        }
+        // MVI r,byte
+        (0, _, 6) => state.set_reg(ddd, byte_1),
+        // RLC
+        (0, 0, 7) => {
+            state.condition.cy = (state.a & 0b10000000) != 0;
+            state.a = state.a.rotate_left(1);
+        }
+        // RRC
+        (0, 1, 7) => {
+            state.condition.cy = (state.a & 1) != 0;
+            state.a = state.a.rotate_right(1);
+        }
+        // RAL
+        (0, 2, 7) => {
+            let init_carry = state.condition.cy;
+            state.condition.cy = (state.a & 0b10000000) != 0;
+            state.a = state.a << 1 | init_carry as u8;
+        }
+        // RAR
+        (0, 3, 7) => {
+            let init_carry = state.condition.cy;
+            state.condition.cy = (state.a & 1) != 0;
+            state.a = state.a >> 1 | (init_carry as u8) << 7;
+        }
+        // DAA
+        (0, 4, 7) => state.daa(),
+        // CMA (not) - doesn't affect flags
+        (0, 5, 7) => state.a = !state.a,
+        // STC
+        (0, 6, 7) => state.condition.cy = true,
+        // CMC
+        (0, 7, 7) => state.condition.cy = !state.condition.cy,
+
+        // HLT, where MOV M,M would be
+        (1, 6, 6) => state.halted = true,
+        // MOV d,s
+        (1, _, _) => {
+            let value = state.reg(sss);
+            state.set_reg(ddd, value);
+        }
+
+        // ADD ADC SUB SBB ANA XRA ORA CMP s
+        (2, _, _) => {
+            let value = state.reg(sss);
+            state.alu(ddd, value);
+        }
+
+        // Rccc
+        (3, _, 0) => {
+            if state.condition.check(ddd) {
+                state.pc = state.pop();
+                return CONDITIONAL_CYCLES;
+            }
+        }
+        // POP rp, where SP's slot is PSW
+        (3, _, 1) if ddd & 1 == 0 => {
+            let value = state.pop();
+            state.set_stack_pair(rp, value);
+        }
+        // RET, 0xd9 is an undocumented alias
+        (3, 1, 1) | (3, 3, 1) => state.pc = state.pop(),
+        // PCHL
+        (3, 5, 1) => state.pc = state.pair(HL),
+        // SPHL
+        (3, 7, 1) => state.sp = state.pair(HL),
+        // Jccc word
+        (3, _, 2) => {
+            if state.condition.check(ddd) {
+                state.pc = word;
+            }
+        }
+        // JMP word, 0xcb is an undocumented alias
+        (3, 0, 3) | (3, 1, 3) => state.pc = word,
+        // OUT port
+        (3, 2, 3) => {
+            state.watchpoints.output(byte_1, state.a);
+            io.output(byte_1, state.a);
+        }
+        // IN port
+        (3, 3, 3) => {
+            state.a = io.input(byte_1);
+            state.watchpoints.input(byte_1, state.a);
+        }
+        // XTHL
+        (3, 4, 3) => {
+            let top = state.pop();
+            state.push(state.pair(HL));
+            state.set_pair(HL, top);
+        }
+        // XCHG
+        (3, 5, 3) => {
+            let de = state.pair(DE);
+            state.set_pair(DE, state.pair(HL));
+            state.set_pair(HL, de);
+        }
+        // DI
+        (3, 6, 3) => state.interrupts = false,
+        // EI
+        (3, 7, 3) => {
+            state.interrupts = true;
+            state.interrupt_delay = true;
+        }
+        // Cccc word
+        (3, _, 4) => {
+            if state.condition.check(ddd) {
+                state.push(state.pc);
+                state.pc = word;
+                return CONDITIONAL_CYCLES;
+            }
+        }
+        // PUSH rp, where SP's slot is PSW
+        (3, _, 5) if ddd & 1 == 0 => state.push(state.stack_pair(rp)),
+        // CALL word, 0xdd 0xed 0xfd are undocumented aliases
+        (3, _, 5) => {
+            state.push(state.pc);
+            state.pc = word;
+        }
+        // ADI ACI SUI SBI ANI XRI ORI CPI byte
+        (3, _, 6) => state.alu(ddd, byte_1),
+        // RST n
+        (3, _, 7) => {
+            state.push(state.pc);
+            state.pc = (ddd as u16) << 3;
+        }
+
+        // The group is only two bits
+        _ => unreachable!(),
+    }
+    0
+}
+
+// Register pairs as numbered in the RP field
+const BC: u8 = 0;
+const DE: u8 = 1;
+const HL: u8 = 2;
+
+// Register access and arithmetic shared by the register, memory and
+// immediate forms
+impl StateIntel8080 {
+    // Register named by a DDD or SSS field, B C D E H L M A, where M is the
+    // memory HL points at
+    fn reg(&mut self, r: u8) -> u8 {
+        match r {
+            0 => self.b,
+            1 => self.c,
+            2 => self.d,
+            3 => self.e,
+            4 => self.h,
+            5 => self.l,
+            6 => self.read(self.pair(HL)),
+            _ => self.a,
+        }
+    }
+
+    fn set_reg(&mut self, r: u8, value: u8) {
+        match r {
+            0 => self.b = value,
+            1 => self.c = value,
+            2 => self.d = value,
+            3 => self.e = value,
+            4 => self.h = value,
+            5 => self.l = value,
+            6 => self.write(self.pair(HL), value),
+            _ => self.a = value,
+        }
+    }
+
+    // Register pair named by an RP field, BC DE HL SP
+    fn pair(&self, rp: u8) -> u16 {
+        match rp {
+            BC => (self.b as u16) << 8 | self.c as u16,
+            DE => (self.d as u16) << 8 | self.e as u16,
+            HL => (self.h as u16) << 8 | self.l as u16,
+            _ => self.sp,
+        }
+    }
+
+    fn set_pair(&mut self, rp: u8, value: u16) {
+        match rp {
+            BC => {
+                self.b = (value >> 8) as u8;
+                self.c = value as u8;
+            }
+            DE => {
+                self.d = (value >> 8) as u8;
+                self.e = value as u8;
+            }
+            HL => {
+                self.h = (value >> 8) as u8;
+                self.l = value as u8;
+            }
+            _ => self.sp = value,
+        }
+    }
+
+    // PUSH and POP use the SP slot for A and the flags
+    fn stack_pair(&self, rp: u8) -> u16 {
+        match rp {
+            BC | DE | HL => self.pair(rp),
+            _ => (self.a as u16) << 8 | self.condition.to_psw() as u16,
+        }
+    }
+
+    fn set_stack_pair(&mut self, rp: u8, value: u16) {
+        match rp {
+            BC | DE | HL => self.set_pair(rp, value),
+            _ => {
+                self.a = (value >> 8) as u8;
+                self.condition.set_psw(value as u8);
+            }
+        }
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.write(self.sp.wrapping_add(1), (value >> 8) as u8);
+        self.write(self.sp, value as u8);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let value = self.read(self.sp) as u16 | (self.read(self.sp.wrapping_add(1)) as u16) << 8;
+        self.sp = self.sp.wrapping_add(2);
+        value
+    }
+
+    // The operation in the DDD field of 10DDDSSS and 11DDD110
+    fn alu(&mut self, op: u8, value: u8) {
+        match op {
+            0 => self.add(value, false),
+            1 => self.add(value, self.condition.cy),
+            2 => self.a = self.sub(value, false),
+            3 => self.a = self.sub(value, self.condition.cy),
+            4 => self.and(value),
+            5 => self.xor(value),
+            6 => self.or(value),
+            // CMP only sets the flags
+            _ => {
+                self.sub(value, false);
+            }
+        }
+    }
+
+    // ADD, ADC, ADI, ACI
+    fn add(&mut self, value: u8, carry: bool) {
+        self.condition.set_add_flags(self.a, value, carry);
+        self.a = self.a.wrapping_add(value).wrapping_add(carry as u8);
+    }
+
+    // SUB, SBB, SUI, SBI, returns the result so CMP can throw it away
+    fn sub(&mut self, value: u8, borrow: bool) -> u8 {
+        self.condition.set_sub_flags(self.a, value, borrow);
+        self.a.wrapping_sub(value).wrapping_sub(borrow as u8)
+    }
+
+    fn and(&mut self, value: u8) {
+        self.condition.set_and_flags(self.a, value);
+        self.a &= value;
+    }
+
+    fn xor(&mut self, value: u8) {
+        self.a ^= value;
+        self.condition.set_logic_flags(self.a);
+    }
+
+    fn or(&mut self, value: u8) {
+        self.a |= value;
+        self.condition.set_logic_flags(self.a);
+    }
+
+    // Adjusts A to packed BCD after an addition. The correction is added like
+    // ADI, so AC comes from that addition, but CY is only ever set, not cleared.
+    fn daa(&mut self) {
+        let low = self.a & 0x0f;
+        let high = self.a >> 4;
+        let mut correction: u8 = 0;
+        let mut carry = self.condition.cy;
+
+        if low > 9 || self.condition.ac {
+            correction |= 0x06;
+        }
+        if high > 9 || carry || (high >= 9 && low > 9) {
+            correction |= 0x60;
+            carry = true;
+        }
+
+        self.add(correction, false);
+        self.condition.cy = carry;
+    }
+}
+
+// Debug printing
+#[allow(dead_code)]
+pub fn print_registers(state: &StateIntel8080) {
+    print!("a = {:02x}, ", state.a);
+    print!("bc = {:02x}{:02x}, ", state.b, state.c);
+    print!("de = {:02x}{:02x}, ", state.d, state.e);
+    print!("hl = {:02x}{:02x}, ", state.h, state.l);
+    print!("pc = {:04x}, ", state.pc);
+    print!("sp = {:04x}, ", state.sp);
+    print!("i = {:?}, ", state.interrupts);
+    println!("{:?}", state.condition);
+}
+
+// Bytes taken by each instruction, the opcode plus any immediate data
+pub fn instruction_length(opcode: u8) -> u16 {
+    match opcode {
+        // LXI, SHLD, LHLD, STA, LDA
+        0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2a | 0x32 | 0x3a => 3,
+        // Jumps and calls, including the undocumented aliases
+        0xc2 | 0xc3 | 0xc4 | 0xca | 0xcb | 0xcc | 0xcd | 0xd2 | 0xd4 | 0xda | 0xdc | 0xdd
+        | 0xe2 | 0xe4 | 0xea | 0xec | 0xed | 0xf2 | 0xf4 | 0xfa | 0xfc | 0xfd => 3,
+        // MVI, immediate arithmetic, IN and OUT
+        0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e | 0xc6 | 0xce | 0xd3 | 0xd6
+        | 0xdb | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => 2,
+        _ => 1,
+    }
+}
+
+// Get cycles for every instruction
+// Conditional calls and returns list the cost when the condition fails, taking
+// them costs CONDITIONAL_CYCLES more
+pub fn get_cycles(opcode: u8) -> u8 {
+    // Extracted from https://pastraiser.com/cpu/i8080/i8080_opcodes.html using excel + vs code,
+    // checked against the 8080 Programmers Manual
+    let cycles = [
+        4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, // 00
+        4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, // 10
+        4, 10, 16, 5, 5, 5, 7, 4, 4, 10, 16, 5, 5, 5, 7, 4, // 20
+        4, 10, 13, 5, 10, 10, 10, 4, 4, 10, 13, 5, 5, 5, 7, 4, // 30
+        5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, // 40
+        5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, // 50
+        5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, // 60
+        7, 7, 7, 7, 7, 7, 7, 7, 5, 5, 5, 5, 5, 5, 7, 5, // 70
+        4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 80
+        4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 90
+        4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // a0
+        4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // b0
+        5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11, // c0
+        5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11, // d0
+        5, 10, 10, 18, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11, // e0
+        5, 10, 10, 4, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11, // f0
+    ];
+
+    cycles[opcode as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // Runs a program to its HLT
+    fn run(source: &str) -> Cpu {
+        let mut cpu = Cpu::new(assemble(source).unwrap().bytes);
+        while !cpu.halted() {
+            cpu.step();
+        }
+        cpu
+    }
+
+    // A after adding `value` to `a` and DAA, with CY and AC
+    fn daa(a: u8, value: u8) -> (u8, bool, bool) {
+        let cpu = run(&format!("MVI A,${:02x}\nADI ${:02x}\nDAA\nHLT", a, value));
+        (cpu.state.a, cpu.state.condition.cy, cpu.state.condition.ac)
+    }
+
+    #[test]
+    fn decimal_adjust() {
+        // Low nibble over 9, and a carry out of the low nibble
+        assert_eq!(daa(0x05, 0x06), (0x11, false, true));
+        assert_eq!(daa(0x09, 0x09), (0x18, false, false));
+        // High nibble over 9, and a carry out of the high nibble
+        assert_eq!(daa(0x50, 0x60), (0x10, true, false));
+        assert_eq!(daa(0x90, 0x90), (0x80, true, false));
+        // Both nibbles
+        assert_eq!(daa(0x99, 0x01), (0x00, true, true));
+        assert_eq!(daa(0x38, 0x45), (0x83, false, true));
+
+        // DAA only ever sets CY
+        let cpu = run("STC\nMVI A,$15\nDAA\nHLT");
+        assert_eq!(cpu.state.a, 0x75);
+        assert!(cpu.state.condition.cy);
+    }
+
+    #[test]
+    fn compare_keeps_a() {
+        let cpu = run("MVI A,$05\nCPI $06\nHLT");
+        assert_eq!(cpu.state.a, 0x05);
+        assert!(cpu.state.condition.cy && !cpu.state.condition.z);
+        let cpu = run("MVI A,$05\nMVI B,$05\nCMP B\nHLT");
+        assert_eq!(cpu.state.a, 0x05);
+        assert!(cpu.state.condition.z && cpu.state.condition.ac && !cpu.state.condition.cy);
+        let cpu = run("STC\nMVI A,$05\nSBI $05\nHLT");
+        assert_eq!(cpu.state.a, 0xff);
+        assert!(cpu.state.condition.cy && cpu.state.condition.s);
+    }
+
+    #[test]
+    fn increment_leaves_carry() {
+        let cpu = run("STC\nMVI B,$0f\nINR B\nHLT");
+        assert_eq!(cpu.state.b, 0x10);
+        assert!(cpu.state.condition.ac && cpu.state.condition.cy);
+        let cpu = run("STC\nMVI B,$00\nDCR B\nHLT");
+        assert_eq!(cpu.state.b, 0xff);
+        assert!(!cpu.state.condition.ac && cpu.state.condition.cy);
+    }
+
+    #[test]
+    fn push_psw_fixed_bits() {
+        // Z, AC, P and CY set by the addition
+        let cpu = run("LXI SP,$100\nMVI A,$ff\nADI 1\nPUSH PSW\nHLT");
+        assert_eq!(cpu.state.memory[0xfe..0x100], [0x57, 0x00]);
+        // Every bit popped in, the unused ones come back fixed
+        let cpu = run("LXI SP,$100\nLXI B,$ffff\nPUSH B\nPOP PSW\nPUSH PSW\nHLT");
+        assert_eq!(cpu.state.memory[0xfe..0x100], [0xd7, 0xff]);
+    }
+
+    // RST 1 puts a 1 in C and halts, the program starts at `start`
+    const HANDLER: &str = "
+        JMP     start
+        ORG     8
+        MVI     C,1
+        HLT
+start:  LXI     SP,$100
+";
+
+    // Steps until PC reaches `address`, or halts
+    fn step_to(cpu: &mut Cpu, address: u16) {
+        while cpu.state.pc != address && !cpu.halted() {
+            cpu.step();
+        }
+    }
+
+    // The return address the interrupt pushed
+    fn pushed(cpu: &Cpu) -> u16 {
+        let sp = cpu.state.sp as usize;
+        cpu.state.memory[sp] as u16 | (cpu.state.memory[sp + 1] as u16) << 8
+    }
+
+    #[test]
+    fn ei_waits_an_instruction() {
+        let program = assemble(&format!("{}EI\nINR B\nsecond: INR B\nHLT", HANDLER)).unwrap();
+        let mut cpu = Cpu::new(program.bytes);
+        cpu.state.generate_interrupt(1);
+        while !cpu.halted() {
+            cpu.step();
+        }
+        assert_eq!(cpu.state.c, 1);
+        assert_eq!(cpu.state.b, 1);
+        assert_eq!(pushed(&cpu), program.symbols["second"]);
+        assert!(!cpu.state.interrupts);
+    }
+
+    #[test]
+    fn interrupt_waits_for_ei() {
+        let program = assemble(&format!(
+            "{}DI\nINR B\nINR B\nINR B\nEI\nNOP\nafter: NOP\nHLT",
+            HANDLER
+        ))
+        .unwrap();
+        let mut cpu = Cpu::new(program.bytes);
+        step_to(&mut cpu, program.symbols["start"]);
+        cpu.state.generate_interrupt(1);
+        step_to(&mut cpu, 0x0008);
+        // Latched through the DI section, taken after the instruction after EI
+        assert_eq!(cpu.state.b, 3);
+        assert_eq!(pushed(&cpu), program.symbols["after"]);
+        assert!(cpu.state.pending_interrupt.is_none());
+    }
+
+    #[test]
+    fn interrupt_wakes_hlt() {
+        let program = assemble(&format!("{}EI\nHLT\nafter: NOP", HANDLER)).unwrap();
+        let mut cpu = Cpu::new(program.bytes);
+        while !cpu.halted() {
+            cpu.step();
+        }
+        let cycles = cpu.cycles;
+        for _ in 0..3 {
+            assert_eq!(cpu.step(), HALT_CYCLES);
+        }
+        assert!(cpu.halted());
+        assert_eq!(cpu.cycles, cycles + 3 * HALT_CYCLES as u64);
+
+        cpu.state.generate_interrupt(1);
+        assert_eq!(cpu.step(), 11);
+        assert!(!cpu.halted());
+        assert_eq!(cpu.state.pc, 0x0008);
+        assert_eq!(pushed(&cpu), program.symbols["after"]);
+    }
+
+    #[test]
+    fn bus_supplies_the_instruction() {
+        let program = assemble(&format!("{}EI\nhere: JMP here", HANDLER)).unwrap();
+        let here = program.symbols["here"];
+        let mut cpu = Cpu::new(program.bytes);
+        step_to(&mut cpu, here);
+        cpu.step();
+
+        // A CALL with its address
+        cpu.state.request_interrupt(&[0xcd, 0x34, 0x12]);
+        assert_eq!(cpu.step(), 17);
+        assert_eq!(cpu.state.pc, 0x1234);
+        assert_eq!(pushed(&cpu), here);
+
+        // Any RST
+        cpu.state.pc = here;
+        cpu.state.interrupts = true;
+        cpu.state.request_interrupt(&[0xff]);
+        assert_eq!(cpu.step(), 11);
+        assert_eq!(cpu.state.pc, 0x0038);
+        assert_eq!(pushed(&cpu), here);
+    }
+
+    #[test]
+    fn conditional_calls_and_returns() {
+        // Z is set throughout, so the NZ forms fall through and the Z ones
+        // are taken
+        let mut cpu = Cpu::new(
+            assemble(
+                "
+        LXI     SP,$100
+        XRA     A
+        CNZ     sub
+        CZ      sub
+        HLT
+sub:    RNZ
+        RZ
+",
+            )
+            .unwrap()
+            .bytes,
+        );
+        let mut cycles = Vec::new();
+        while !cpu.halted() {
+            cycles.push(cpu.step());
+        }
+        assert_eq!(
+            cycles,
+            [
+                10,
+                4,
+                11,
+                11 + CONDITIONAL_CYCLES,
+                5,
+                5 + CONDITIONAL_CYCLES,
+                7
+            ]
+        );
+    }
+}
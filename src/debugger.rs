@@ -0,0 +1,573 @@
+// A monitor for poking at a running machine. It takes one command line at a
+// time and hands back what to print, so a frontend can feed it from a
+// terminal while the game keeps drawing, or run it with no window at all.
+//
+//     s [N]          step N instructions, 1 by default
+//     n              step over a call or RST
+//     rf [N]         go back N frames, 1 by default, on a target that keeps
+//                    them for rewinding
+//     c              continue until a breakpoint
+//     g ADDR         continue until ADDR
+//     b [ADDR]       set a breakpoint, or list them
+//     d [ADDR]       delete a breakpoint, or all of them
+//     w [ADDR [LEN] [r|w]]
+//                    watch memory for reads, writes or both, or list the
+//                    watchpoints
+//     p PORT [in|out] [VALUE]
+//                    watch a port, only for VALUE if it's given
+//     dw [N]         delete watchpoint N from the list, or all of them
+//     r              registers and flags
+//     x ADDR [LEN]   hex dump
+//     e ADDR BYTES   write bytes into memory, ROM included
+//     l [ADDR] [N]   disassemble, around PC by default
+//     q              quit
+//
+// Addresses are hex, with or without a $ or 0x, a symbol name, or `pc`. An
+// empty line repeats the last command.
+use crate::cpu::Cpu;
+use crate::disassembler::{decode, Instruction};
+use crate::symbols::Symbols;
+use crate::watch::{Access, Hit, Watch};
+use std::fmt::Write;
+
+// Something the debugger can drive, a bare cpu or a whole machine that
+// raises its own interrupts as it runs
+pub trait Target {
+    fn cpu(&mut self) -> &mut Cpu;
+    // Runs until the cpu's cycle total reaches `cycle`, false if it stopped
+    // at a breakpoint or watchpoint first
+    fn run_until(&mut self, cycle: u64) -> bool;
+    // Goes back to the start of the last frame kept for rewinding, or the
+    // frame before if it's already there. False if there's none to go to.
+    fn back_frame(&mut self) -> bool {
+        false
+    }
+}
+
+impl Target for Cpu {
+    fn cpu(&mut self) -> &mut Cpu {
+        self
+    }
+
+    fn run_until(&mut self, cycle: u64) -> bool {
+        Cpu::run_until(self, cycle)
+    }
+}
+
+// Instructions listed before PC when disassembling around it
+const CONTEXT: usize = 4;
+const LIST_LINES: usize = 12;
+const DUMP_LENGTH: usize = 64;
+const DUMP_WIDTH: usize = 16;
+
+const HELP: &str = "\
+s [N]          step N instructions
+n              step over a call or RST
+rf [N]         go back N frames
+c              continue until a breakpoint
+g ADDR         continue until ADDR
+b [ADDR]       set a breakpoint, or list them
+d [ADDR]       delete a breakpoint, or all of them
+w [ADDR [LEN] [r|w]]
+               watch memory, or list the watchpoints
+p PORT [in|out] [VALUE]
+               watch a port, only for VALUE if it's given
+dw [N]         delete watchpoint N, or all of them
+r              registers and flags
+x ADDR [LEN]   hex dump
+e ADDR BYTES   write bytes into memory
+l [ADDR] [N]   disassemble, around PC by default
+q              quit
+";
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    pub symbols: Symbols,
+    // Set by c, g and n, the frontend keeps calling run while it's set
+    pub running: bool,
+    pub quit: bool,
+    // Breakpoint set by g or n, removed again when anything stops
+    temporary: Option<u16>,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new(symbols: Symbols) -> Debugger {
+        Debugger {
+            symbols,
+            ..Default::default()
+        }
+    }
+
+    // Carries out one command line, returning what it has to say
+    pub fn command<T: Target>(&mut self, target: &mut T, line: &str) -> String {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.first() {
+            None => Ok(String::new()),
+            Some(&"s") => self.step(target, words.get(1)),
+            Some(&"n") => self.step_over(target),
+            Some(&"rf") => self.back_frames(target, words.get(1)),
+            Some(&"c") => {
+                self.running = true;
+                Ok(String::new())
+            }
+            Some(&"g") => match words.get(1) {
+                Some(word) => self.address(target.cpu(), word).map(|address| {
+                    self.set_temporary(target.cpu(), address);
+                    self.running = true;
+                    String::new()
+                }),
+                None => Err("g needs an address".to_string()),
+            },
+            Some(&"b") => self.breakpoint(target.cpu(), words.get(1)),
+            Some(&"d") => self.delete(target.cpu(), words.get(1)),
+            Some(&"w") => self.watch(target.cpu(), &words[1..]),
+            Some(&"p") => self.watch_port(target.cpu(), &words[1..]),
+            Some(&"dw") => self.delete_watch(target.cpu(), words.get(1)),
+            Some(&"r") => Ok(self.registers(target.cpu())),
+            Some(&"x") => self.dump(target.cpu(), &words[1..]),
+            Some(&"e") => self.edit(target.cpu(), &words[1..]),
+            Some(&"l") => self.list(target.cpu(), &words[1..]),
+            Some(&"q") => {
+                self.quit = true;
+                self.running = false;
+                Ok(String::new())
+            }
+            Some(&"h") | Some(&"?") => Ok(HELP.to_string()),
+            Some(word) => Err(format!("unknown command `{}`, h for help", word)),
+        };
+        match result {
+            Ok(output) => output,
+            Err(error) => format!("{}\n", error),
+        }
+    }
+
+    // Runs the target on towards `cycle` if a command set it going. Returns
+    // where it stopped if it hit a breakpoint or watchpoint on the way.
+    pub fn run<T: Target>(&mut self, target: &mut T, cycle: u64) -> Option<String> {
+        if !self.running || target.run_until(cycle) {
+            return None;
+        }
+        Some(self.stop(target))
+    }
+
+    // Stops the target wherever it is, for a frontend interrupting it
+    pub fn stop<T: Target>(&mut self, target: &mut T) -> String {
+        self.running = false;
+        let cpu = target.cpu();
+        if let Some(address) = self.temporary.take() {
+            cpu.breakpoints.remove(&address);
+        }
+        format!(
+            "{}stopped at {}\n",
+            self.hit(cpu),
+            self.line(cpu, cpu.state.pc)
+        )
+    }
+
+    fn step<T: Target>(&mut self, target: &mut T, count: Option<&&str>) -> Result<String, String> {
+        for _ in 0..parse_count(count)? {
+            let next = target.cpu().cycles + 1;
+            if !target.run_until(next) {
+                break;
+            }
+        }
+        let cpu = target.cpu();
+        Ok(format!(
+            "{}{}\n",
+            self.hit(cpu),
+            self.line(cpu, cpu.state.pc)
+        ))
+    }
+
+    // Calls are run to the instruction after them rather than stepped into
+    fn step_over<T: Target>(&mut self, target: &mut T) -> Result<String, String> {
+        let cpu = target.cpu();
+        let instruction = decode_at(cpu, cpu.state.pc);
+        // CALL and Ccc are the only instructions starting with C that jump
+        let mnemonic = instruction.mnemonic;
+        if mnemonic == "RST" || (mnemonic.starts_with('C') && instruction.target.is_some()) {
+            self.set_temporary(cpu, instruction.next());
+            self.running = true;
+            return Ok(String::new());
+        }
+        self.step(target, None)
+    }
+
+    fn back_frames<T: Target>(
+        &mut self,
+        target: &mut T,
+        count: Option<&&str>,
+    ) -> Result<String, String> {
+        let count = parse_count(count)?;
+        let mut frames = 0;
+        while frames < count && target.back_frame() {
+            frames += 1;
+        }
+        if frames == 0 {
+            return Err("no earlier frames to go back to".to_string());
+        }
+        let cpu = target.cpu();
+        Ok(format!(
+            "back {} frame{} to cycles={}\n{}\n",
+            frames,
+            if frames == 1 { "" } else { "s" },
+            cpu.cycles,
+            self.line(cpu, cpu.state.pc)
+        ))
+    }
+
+    fn set_temporary(&mut self, cpu: &mut Cpu, address: u16) {
+        if cpu.breakpoints.insert(address) {
+            self.temporary = Some(address);
+        }
+    }
+
+    fn breakpoint(&mut self, cpu: &mut Cpu, word: Option<&&str>) -> Result<String, String> {
+        if let Some(word) = word {
+            let address = self.address(cpu, word)?;
+            cpu.breakpoints.insert(address);
+            if self.temporary == Some(address) {
+                self.temporary = None;
+            }
+            return Ok(String::new());
+        }
+        let mut output = String::new();
+        for address in &cpu.breakpoints {
+            writeln!(output, "{}", self.line(cpu, *address)).unwrap();
+        }
+        Ok(output)
+    }
+
+    fn delete(&mut self, cpu: &mut Cpu, word: Option<&&str>) -> Result<String, String> {
+        match word {
+            Some(word) => {
+                let address = self.address(cpu, word)?;
+                if !cpu.breakpoints.remove(&address) {
+                    return Err(format!(
+                        "no breakpoint at {}",
+                        self.symbols.describe(address)
+                    ));
+                }
+            }
+            None => cpu.breakpoints.clear(),
+        }
+        Ok(String::new())
+    }
+
+    fn watch(&mut self, cpu: &mut Cpu, words: &[&str]) -> Result<String, String> {
+        let start = match words.first() {
+            Some(word) => self.address(cpu, word)?,
+            None => return Ok(self.watches(cpu)),
+        };
+        let mut length = 1;
+        let mut access = Access::Both;
+        for word in &words[1..] {
+            match *word {
+                "r" => access = Access::Read,
+                "w" => access = Access::Write,
+                "rw" => access = Access::Both,
+                _ => match number(word) {
+                    Some(value) if value > 0 => length = value,
+                    _ => return Err(format!("bad length `{}`", word)),
+                },
+            }
+        }
+        let end = start.saturating_add(length - 1);
+        cpu.state
+            .watchpoints
+            .watches
+            .push(Watch::Memory { start, end, access });
+        Ok(String::new())
+    }
+
+    fn watch_port(&mut self, cpu: &mut Cpu, words: &[&str]) -> Result<String, String> {
+        let port = match words.first() {
+            Some(word) => match number(word) {
+                Some(port) if port <= 0xff => port as u8,
+                _ => return Err(format!("bad port `{}`", word)),
+            },
+            None => return Err("p needs a port".to_string()),
+        };
+        let mut access = Access::Both;
+        let mut value = None;
+        for word in &words[1..] {
+            match *word {
+                "in" => access = Access::Read,
+                "out" => access = Access::Write,
+                _ => match number(word) {
+                    Some(byte) if byte <= 0xff => value = Some(byte as u8),
+                    _ => return Err(format!("bad value `{}`", word)),
+                },
+            }
+        }
+        cpu.state.watchpoints.watches.push(Watch::Port {
+            port,
+            access,
+            value,
+        });
+        Ok(String::new())
+    }
+
+    fn delete_watch(&mut self, cpu: &mut Cpu, word: Option<&&str>) -> Result<String, String> {
+        let watches = &mut cpu.state.watchpoints.watches;
+        match word {
+            Some(word) => match word.parse::<usize>() {
+                Ok(index) if index < watches.len() => {
+                    watches.remove(index);
+                }
+                _ => return Err(format!("no watchpoint `{}`", word)),
+            },
+            None => watches.clear(),
+        }
+        Ok(String::new())
+    }
+
+    // The watchpoints, numbered for dw
+    fn watches(&self, cpu: &Cpu) -> String {
+        let mut output = String::new();
+        for (index, watch) in cpu.state.watchpoints.watches.iter().enumerate() {
+            let description = match *watch {
+                Watch::Memory { start, end, access } => {
+                    let kind = match access {
+                        Access::Read => "read",
+                        Access::Write => "write",
+                        Access::Both => "read/write",
+                    };
+                    let range = if start == end {
+                        self.symbols.describe(start)
+                    } else {
+                        format!(
+                            "{}-{}",
+                            self.symbols.describe(start),
+                            self.symbols.describe(end)
+                        )
+                    };
+                    format!("{} {}", kind, range)
+                }
+                Watch::Port {
+                    port,
+                    access,
+                    value,
+                } => {
+                    let kind = match access {
+                        Access::Read => "IN",
+                        Access::Write => "OUT",
+                        Access::Both => "IN/OUT",
+                    };
+                    match value {
+                        Some(value) => format!("{} ${:02x} = ${:02x}", kind, port, value),
+                        None => format!("{} ${:02x}", kind, port),
+                    }
+                }
+            };
+            writeln!(output, "{}  {}", index, description).unwrap();
+        }
+        output
+    }
+
+    // What tripped a watchpoint, if anything did
+    fn hit(&self, cpu: &mut Cpu) -> String {
+        match cpu.state.watchpoints.hit.take() {
+            Some(Hit::Read { address, value }) => {
+                format!(
+                    "read ${:02x} from {}\n",
+                    value,
+                    self.symbols.describe(address)
+                )
+            }
+            Some(Hit::Write { address, value }) => {
+                format!(
+                    "wrote ${:02x} to {}\n",
+                    value,
+                    self.symbols.describe(address)
+                )
+            }
+            Some(Hit::Input { port, value }) => format!("IN ${:02x} gave ${:02x}\n", port, value),
+            Some(Hit::Output { port, value }) => format!("OUT ${:02x} sent ${:02x}\n", port, value),
+            None => String::new(),
+        }
+    }
+
+    fn registers(&self, cpu: &Cpu) -> String {
+        let state = &cpu.state;
+        let condition = &state.condition;
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        let flags: String = [
+            flag(condition.s, 'S'),
+            flag(condition.z, 'Z'),
+            flag(condition.ac, 'A'),
+            flag(condition.p, 'P'),
+            flag(condition.cy, 'C'),
+        ]
+        .iter()
+        .collect();
+        let mut output = format!(
+            "A={:02x} BC={:02x}{:02x} DE={:02x}{:02x} HL={:02x}{:02x} SP={:04x} PC={:04x} {} cycles={}",
+            state.a, state.b, state.c, state.d, state.e, state.h, state.l, state.sp, state.pc, flags, cpu.cycles
+        );
+        if state.interrupts {
+            output.push_str(" EI");
+        }
+        if state.halted {
+            output.push_str(" HALT");
+        }
+        output.push('\n');
+        output
+    }
+
+    fn dump(&self, cpu: &mut Cpu, words: &[&str]) -> Result<String, String> {
+        let start = match words.first() {
+            Some(word) => self.address(cpu, word)?,
+            None => return Err("x needs an address".to_string()),
+        };
+        let length = match words.get(1) {
+            Some(word) => number(word).ok_or_else(|| format!("bad length `{}`", word))? as usize,
+            None => DUMP_LENGTH,
+        };
+
+        let mut output = String::new();
+        for row in (0..length).step_by(DUMP_WIDTH) {
+            let address = start.wrapping_add(row as u16);
+            let bytes: Vec<u8> = (0..DUMP_WIDTH.min(length - row))
+                .map(|offset| cpu.state.peek(address.wrapping_add(offset as u16)))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = bytes
+                .iter()
+                .map(|&byte| {
+                    if (0x20..0x7f).contains(&byte) {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(output, "{:04x}  {:<48} {}", address, hex.join(" "), text).unwrap();
+        }
+        Ok(output)
+    }
+
+    fn edit(&self, cpu: &mut Cpu, words: &[&str]) -> Result<String, String> {
+        if words.len() < 2 {
+            return Err("e needs an address and bytes".to_string());
+        }
+        let start = self.address(cpu, words[0])?;
+        let mut bytes = Vec::new();
+        for word in &words[1..] {
+            match number(word) {
+                Some(value) if value <= 0xff => bytes.push(value as u8),
+                _ => return Err(format!("bad byte `{}`", word)),
+            }
+        }
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            cpu.state.poke(start.wrapping_add(offset as u16), byte);
+        }
+        Ok(String::new())
+    }
+
+    fn list(&self, cpu: &mut Cpu, words: &[&str]) -> Result<String, String> {
+        let pc = cpu.state.pc;
+        let start = match words.first() {
+            Some(word) => self.address(cpu, word)?,
+            None => back_up(cpu, pc),
+        };
+        let count = match words.get(1) {
+            Some(word) => number(word).ok_or_else(|| format!("bad count `{}`", word))? as usize,
+            None => LIST_LINES,
+        };
+
+        let mut output = String::new();
+        let mut address = start;
+        for _ in 0..count {
+            let marker = if address == pc { '>' } else { ' ' };
+            writeln!(output, "{}{}", marker, self.line(cpu, address)).unwrap();
+            address = decode_at(cpu, address).next();
+        }
+        Ok(output)
+    }
+
+    // One disassembled line, marked with * if there's a breakpoint on it
+    fn line(&self, cpu: &Cpu, address: u16) -> String {
+        let instruction = decode_at(cpu, address);
+        let breakpoint = if cpu.breakpoints.contains(&address) {
+            '*'
+        } else {
+            ' '
+        };
+        let label = match self.symbols.name(address) {
+            Some(name) => format!("{}:", name),
+            None => String::new(),
+        };
+        format!(
+            "{}{:04x}  {:<12} {}",
+            breakpoint,
+            address,
+            label,
+            instruction.with_symbols(&self.symbols)
+        )
+    }
+
+    fn address(&self, cpu: &Cpu, word: &str) -> Result<u16, String> {
+        if word == "pc" {
+            return Ok(cpu.state.pc);
+        }
+        self.symbols
+            .address(word)
+            .or_else(|| number(word))
+            .ok_or_else(|| format!("bad address `{}`", word))
+    }
+}
+
+// Decodes through the memory map, so mirrors and open bus read as the cpu
+// would see them
+fn decode_at(cpu: &Cpu, address: u16) -> Instruction {
+    let bytes: Vec<u8> = (0..3)
+        .map(|offset| cpu.state.peek(address.wrapping_add(offset)))
+        .collect();
+    Instruction {
+        address,
+        ..decode(&bytes, 0)
+    }
+}
+
+// Finds somewhere a few instructions before `pc` that decodes onto it, the
+// earliest start that lines up gives the most context
+fn back_up(cpu: &Cpu, pc: u16) -> u16 {
+    for distance in (1..=CONTEXT as u16 * 3).rev() {
+        let start = pc.wrapping_sub(distance);
+        let mut address = start;
+        let mut steps = 0;
+        while address != pc && address.wrapping_sub(start) < distance {
+            address = decode_at(cpu, address).next();
+            steps += 1;
+        }
+        if address == pc && steps <= CONTEXT {
+            return start;
+        }
+    }
+    pc
+}
+
+// Hex, with or without a $ or 0x
+fn number(word: &str) -> Option<u16> {
+    let hex = word
+        .strip_prefix('$')
+        .or_else(|| word.strip_prefix("0x"))
+        .unwrap_or(word);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+// A decimal count for s and rf, 1 if there isn't one
+fn parse_count(word: Option<&&str>) -> Result<usize, String> {
+    match word {
+        Some(word) => word.parse().map_err(|_| format!("bad count `{}`", word)),
+        None => Ok(1),
+    }
+}
@@ -1,412 +1,251 @@
-#[allow(dead_code)]
-pub fn get_single(buf: &Vec<u8>, cur: usize) -> usize {
-    let mut cursor = cur;
-    match buf[cursor] {
-        0x00 => println!("NOP"),
-        0x01 => {
-            println!("LXI B,${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0x02 => println!("STAX B"),
-        0x03 => println!("INX B"),
-        0x04 => println!("INR B"),
-        0x05 => println!("DCR B"),
-        0x06 => {
-            println!("MVI B,${:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0x07 => println!("RLC"),
-        0x08 => println!("-"),
-        0x09 => println!("DAD B"),
-        0x0a => println!("LDAX B"),
-        0x0b => println!("DCX B"),
-        0x0c => println!("INR C"),
-        0x0d => println!("DCR C"),
-        0x0e => {
-            println!("MVI C,${:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0x0f => println!("RRC"),
-        0x10 => println!("-"),
-        0x11 => {
-            println!("LXI D,${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0x12 => println!("STAX D"),
-        0x13 => println!("INX D"),
-        0x14 => println!("INR D"),
-        0x15 => println!("DCR D"),
-        0x16 => {
-            println!("MVI D,${:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0x17 => println!("RAL"),
-        0x18 => println!("-"),
-        0x19 => println!("DAD D"),
-        0x1a => println!("LDAX D"),
-        0x1b => println!("DCX D"),
-        0x1c => println!("INR E"),
-        0x1d => println!("DCR E"),
-        0x1e => {
-            println!("MVI E,${:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0x1f => println!("RAR"),
-        0x20 => println!("-"),
-        0x21 => {
-            println!("LXI H,${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0x22 => {
-            println!("SHLD ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0x23 => println!("INX H"),
-        0x24 => println!("INR H"),
-        0x25 => println!("DCR H"),
-        0x26 => {
-            println!("MVI H,${:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0x27 => println!("DAA"),
-        0x28 => println!("-"),
-        0x29 => println!("DAD H"),
-        0x2a => {
-            println!("LHLD ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0x2b => println!("DCX H"),
-        0x2c => println!("INR L"),
-        0x2d => println!("DCR L"),
-        0x2e => {
-            println!("MVI L,${:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0x2f => println!("CMA"),
-        0x30 => println!("-"),
-        0x31 => {
-            println!("LXI SP,${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0x32 => {
-            println!("STA ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0x33 => println!("INX SP"),
-        0x34 => println!("INR M"),
-        0x35 => println!("DCR M"),
-        0x36 => {
-            println!("MVI M,${:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0x37 => println!("STC"),
-        0x38 => println!("-"),
-        0x39 => println!("DAD SP"),
-        0x3a => {
-            println!("LDA ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0x3b => println!("DCX SP"),
-        0x3c => println!("INR A"),
-        0x3d => println!("DCR A"),
-        0x3e => {
-            println!("MVI A,${:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0x3f => println!("CMC"),
-        0x40 => println!("MOV B,B"),
-        0x41 => println!("MOV B,C"),
-        0x42 => println!("MOV B,D"),
-        0x43 => println!("MOV B,E"),
-        0x44 => println!("MOV B,H"),
-        0x45 => println!("MOV B,L"),
-        0x46 => println!("MOV B,M"),
-        0x47 => println!("MOV B,A"),
-        0x48 => println!("MOV C,B"),
-        0x49 => println!("MOV C,C"),
-        0x4a => println!("MOV C,D"),
-        0x4b => println!("MOV C,E"),
-        0x4c => println!("MOV C,H"),
-        0x4d => println!("MOV C,L"),
-        0x4e => println!("MOV C,M"),
-        0x4f => println!("MOV C,A"),
-        0x50 => println!("MOV D,B"),
-        0x51 => println!("MOV D,C"),
-        0x52 => println!("MOV D,D"),
-        0x53 => println!("MOV D,E"),
-        0x54 => println!("MOV D,H"),
-        0x55 => println!("MOV D,L"),
-        0x56 => println!("MOV D,M"),
-        0x57 => println!("MOV D,A"),
-        0x58 => println!("MOV E,B"),
-        0x59 => println!("MOV E,C"),
-        0x5a => println!("MOV E,D"),
-        0x5b => println!("MOV E,E"),
-        0x5c => println!("MOV E,H"),
-        0x5d => println!("MOV E,L"),
-        0x5e => println!("MOV E,M"),
-        0x5f => println!("MOV E,A"),
-        0x60 => println!("MOV H,B"),
-        0x61 => println!("MOV H,C"),
-        0x62 => println!("MOV H,D"),
-        0x63 => println!("MOV H,E"),
-        0x64 => println!("MOV H,H"),
-        0x65 => println!("MOV H,L"),
-        0x66 => println!("MOV H,M"),
-        0x67 => println!("MOV H,A"),
-        0x68 => println!("MOV L,B"),
-        0x69 => println!("MOV L,C"),
-        0x6a => println!("MOV L,D"),
-        0x6b => println!("MOV L,E"),
-        0x6c => println!("MOV L,H"),
-        0x6d => println!("MOV L,L"),
-        0x6e => println!("MOV L,M"),
-        0x6f => println!("MOV L,A"),
-        0x70 => println!("MOV M,B"),
-        0x71 => println!("MOV M,C"),
-        0x72 => println!("MOV M,D"),
-        0x73 => println!("MOV M,E"),
-        0x74 => println!("MOV M,H"),
-        0x75 => println!("MOV M,L"),
-        0x76 => println!("HLT"),
-        0x77 => println!("MOV M,A"),
-        0x78 => println!("MOV A,B"),
-        0x79 => println!("MOV A,C"),
-        0x7a => println!("MOV A,D"),
-        0x7b => println!("MOV A,E"),
-        0x7c => println!("MOV A,H"),
-        0x7d => println!("MOV A,L"),
-        0x7e => println!("MOV A,M"),
-        0x7f => println!("MOV A,A"),
-        0x80 => println!("ADD B"),
-        0x81 => println!("ADD C"),
-        0x82 => println!("ADD D"),
-        0x83 => println!("ADD E"),
-        0x84 => println!("ADD H"),
-        0x85 => println!("ADD L"),
-        0x86 => println!("ADD M"),
-        0x87 => println!("ADD A"),
-        0x88 => println!("ADC B"),
-        0x89 => println!("ADC C"),
-        0x8a => println!("ADC D"),
-        0x8b => println!("ADC E"),
-        0x8c => println!("ADC H"),
-        0x8d => println!("ADC L"),
-        0x8e => println!("ADC M"),
-        0x8f => println!("ADC A"),
-        0x90 => println!("SUB B"),
-        0x91 => println!("SUB C"),
-        0x92 => println!("SUB D"),
-        0x93 => println!("SUB E"),
-        0x94 => println!("SUB H"),
-        0x95 => println!("SUB L"),
-        0x96 => println!("SUB M"),
-        0x97 => println!("SUB A"),
-        0x98 => println!("SBB B"),
-        0x99 => println!("SBB C"),
-        0x9a => println!("SBB D"),
-        0x9b => println!("SBB E"),
-        0x9c => println!("SBB H"),
-        0x9d => println!("SBB L"),
-        0x9e => println!("SBB M"),
-        0x9f => println!("SBB A"),
-        0xa0 => println!("ANA B"),
-        0xa1 => println!("ANA C"),
-        0xa2 => println!("ANA D"),
-        0xa3 => println!("ANA E"),
-        0xa4 => println!("ANA H"),
-        0xa5 => println!("ANA L"),
-        0xa6 => println!("ANA M"),
-        0xa7 => println!("ANA A"),
-        0xa8 => println!("XRA B"),
-        0xa9 => println!("XRA C"),
-        0xaa => println!("XRA D"),
-        0xab => println!("XRA E"),
-        0xac => println!("XRA H"),
-        0xad => println!("XRA L"),
-        0xae => println!("XRA M"),
-        0xaf => println!("XRA A"),
-        0xb0 => println!("ORA B"),
-        0xb1 => println!("ORA C"),
-        0xb2 => println!("ORA D"),
-        0xb3 => println!("ORA E"),
-        0xb4 => println!("ORA H"),
-        0xb5 => println!("ORA L"),
-        0xb6 => println!("ORA M"),
-        0xb7 => println!("ORA A"),
-        0xb8 => println!("CMP B"),
-        0xb9 => println!("CMP C"),
-        0xba => println!("CMP D"),
-        0xbb => println!("CMP E"),
-        0xbc => println!("CMP H"),
-        0xbd => println!("CMP L"),
-        0xbe => println!("CMP M"),
-        0xbf => println!("CMP A"),
-        0xc0 => println!("RNZ"),
-        0xc1 => println!("POP B"),
-        0xc2 => {
-            println!("JNZ ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xc3 => {
-            println!("JMP ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xc4 => {
-            println!("CNZ ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xc5 => println!("PUSH B"),
-        0xc6 => {
-            println!("ADI {:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0xc7 => println!("RST 0"),
-        0xc8 => println!("RZ"),
-        0xc9 => println!("RET"),
-        0xca => {
-            println!("JZ ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xcb => println!("-"),
-        0xcc => {
-            println!("CZ ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xcd => {
-            println!("CALL ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xce => {
-            println!("ACI {:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0xcf => println!("RST 1"),
-        0xd0 => println!("RNC"),
-        0xd1 => println!("POP D"),
-        0xd2 => {
-            println!("JNC ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xd3 => {
-            println!("OUT {:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0xd4 => {
-            println!("CNC ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xd5 => println!("PUSH D"),
-        0xd6 => {
-            println!("SUI {:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0xd7 => println!("RST 2"),
-        0xd8 => println!("RC"),
-        0xd9 => println!("-"),
-        0xda => {
-            println!("JC ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xdb => {
-            println!("IN {:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0xdc => {
-            println!("CC ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xdd => println!("-"),
-        0xde => {
-            println!("SBI {:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0xdf => println!("RST 3"),
-        0xe0 => println!("RPO"),
-        0xe1 => println!("POP H"),
-        0xe2 => {
-            println!("JPO ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xe3 => println!("XTHL"),
-        0xe4 => {
-            println!("CPO ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xe5 => println!("PUSH H"),
-        0xe6 => {
-            println!("ANI {:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0xe7 => println!("RST 4"),
-        0xe8 => println!("RPE"),
-        0xe9 => println!("PCHL"),
-        0xea => {
-            println!("JPE ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xeb => println!("XCHG"),
-        0xec => {
-            println!("CPE ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xed => println!("-"),
-        0xee => {
-            println!("XRI {:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0xef => println!("RST 5"),
-        0xf0 => println!("RP"),
-        0xf1 => println!("POP PSW"),
-        0xf2 => {
-            println!("JP ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xf3 => println!("DI"),
-        0xf4 => {
-            println!("CP ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xf5 => println!("PUSH PSW"),
-        0xf6 => {
-            println!("ORI {:02x}", buf[cursor + 1]);
-            cursor += 1;
-        }
-        0xf7 => println!("RST 6"),
-        0xf8 => println!("RM"),
-        0xf9 => println!("SPHL"),
-        0xfa => {
-            println!("JM ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xfb => println!("EI"),
-        0xfc => {
-            println!("CM ${:02x}{:02x}", buf[cursor + 2], buf[cursor + 1]);
-            cursor += 2;
-        }
-        0xfd => println!("-"),
-        0xfe => {
-            println!("CPI {:02x}", buf[cursor + 1]);
-            cursor += 1;
+use crate::cpu::instruction_length;
+use crate::symbols::Symbols;
+use std::fmt;
+use std::ops::Range;
+
+// A decoded instruction, for tools that need disassembly as data rather
+// than printed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    // The opcode followed by any immediate data
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    // Where a jump, call or RST goes
+    pub target: Option<u16>,
+    // Opcodes the 8080 documentation leaves out, which alias other instructions
+    pub undocumented: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    // A register, register pair or PSW
+    Register(&'static str),
+    Byte(u8),
+    // A 16 bit immediate or address
+    Word(u16),
+    // The number of an RST
+    Restart(u8),
+}
+
+impl Instruction {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    // Address of the instruction after this one
+    pub fn next(&self) -> u16 {
+        self.address.wrapping_add(self.length())
+    }
+
+    // Operands as source text, with any 16 bit value that has a name given
+    // by the name
+    pub fn operands_with(&self, symbols: &Symbols) -> String {
+        let operands: Vec<String> = self
+            .operands
+            .iter()
+            .map(|operand| match operand {
+                Operand::Word(value) => match symbols.name(*value) {
+                    Some(name) => name.to_string(),
+                    None => operand.to_string(),
+                },
+                _ => operand.to_string(),
+            })
+            .collect();
+        operands.join(",")
+    }
+
+    // Like Display, but with addresses named from `symbols`
+    pub fn with_symbols(&self, symbols: &Symbols) -> String {
+        let operands = self.operands_with(symbols);
+        format!(
+            "{}{}{}{}",
+            if self.undocumented { "*" } else { "" },
+            self.mnemonic,
+            if operands.is_empty() { "" } else { " " },
+            operands
+        )
+    }
+}
+
+impl fmt::Display for Instruction {
+    // Undocumented opcodes print as the instruction they alias, marked with a *
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.with_symbols(&Symbols::new()))
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(name) => write!(f, "{}", name),
+            Operand::Byte(value) => write!(f, "${:02x}", value),
+            Operand::Word(value) => write!(f, "${:04x}", value),
+            Operand::Restart(n) => write!(f, "{}", n),
         }
-        0xff => {
-            println!("RST {:02x}", buf[cursor + 1]);
-            cursor += 1;
+    }
+}
+
+// Names for the fields of an opcode, in the order the 8080 encodes them
+pub(crate) const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+pub(crate) const PAIRS: [&str; 4] = ["B", "D", "H", "SP"];
+pub(crate) const STACK_PAIRS: [&str; 4] = ["B", "D", "H", "PSW"];
+pub(crate) const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+pub(crate) const ALU_IMMEDIATE: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+pub(crate) const JUMPS: [&str; 8] = ["JNZ", "JZ", "JNC", "JC", "JPO", "JPE", "JP", "JM"];
+pub(crate) const CALLS: [&str; 8] = ["CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM"];
+pub(crate) const RETURNS: [&str; 8] = ["RNZ", "RZ", "RNC", "RC", "RPO", "RPE", "RP", "RM"];
+pub(crate) const ROTATES: [&str; 8] = ["RLC", "RRC", "RAL", "RAR", "DAA", "CMA", "STC", "CMC"];
+
+// Decodes the instruction at `address`. Bytes past the end of `memory` read
+// as 0, addresses wrap at 64KB like the cpu's do.
+pub fn decode(memory: &[u8], address: u16) -> Instruction {
+    let read = |offset: u16| {
+        memory
+            .get(address.wrapping_add(offset) as usize)
+            .copied()
+            .unwrap_or(0)
+    };
+    let opcode = read(0);
+    let ddd = ((opcode >> 3) & 0x07) as usize;
+    let sss = (opcode & 0x07) as usize;
+    let rp = ddd >> 1;
+
+    let register = |r: usize| Operand::Register(REGISTERS[r]);
+    let pair = Operand::Register(PAIRS[rp]);
+    let mut undocumented = false;
+
+    // Same decoding as the cpu, group in bits 7-6, DDD in 5-3, SSS in 2-0
+    let (mnemonic, mut operands) = match (opcode >> 6, ddd, sss) {
+        (0, 0, 0) => ("NOP", vec![]),
+        (0, _, 0) => {
+            undocumented = true;
+            ("NOP", vec![])
+        }
+        (0, _, 1) if ddd & 1 == 0 => ("LXI", vec![pair]),
+        (0, _, 1) => ("DAD", vec![pair]),
+        (0, 0, 2) | (0, 2, 2) => ("STAX", vec![pair]),
+        (0, 1, 2) | (0, 3, 2) => ("LDAX", vec![pair]),
+        (0, 4, 2) => ("SHLD", vec![]),
+        (0, 5, 2) => ("LHLD", vec![]),
+        (0, 6, 2) => ("STA", vec![]),
+        (0, 7, 2) => ("LDA", vec![]),
+        (0, _, 3) if ddd & 1 == 0 => ("INX", vec![pair]),
+        (0, _, 3) => ("DCX", vec![pair]),
+        (0, _, 4) => ("INR", vec![register(ddd)]),
+        (0, _, 5) => ("DCR", vec![register(ddd)]),
+        (0, _, 6) => ("MVI", vec![register(ddd)]),
+        (0, _, _) => (ROTATES[ddd], vec![]),
+        (1, 6, 6) => ("HLT", vec![]),
+        (1, _, _) => ("MOV", vec![register(ddd), register(sss)]),
+        (2, _, _) => (ALU[ddd], vec![register(sss)]),
+        (_, _, 0) => (RETURNS[ddd], vec![]),
+        (_, _, 1) if ddd & 1 == 0 => ("POP", vec![Operand::Register(STACK_PAIRS[rp])]),
+        (_, 1, 1) => ("RET", vec![]),
+        (_, 3, 1) => {
+            undocumented = true;
+            ("RET", vec![])
+        }
+        (_, 5, 1) => ("PCHL", vec![]),
+        (_, _, 1) => ("SPHL", vec![]),
+        (_, _, 2) => (JUMPS[ddd], vec![]),
+        (_, 0, 3) => ("JMP", vec![]),
+        (_, 1, 3) => {
+            undocumented = true;
+            ("JMP", vec![])
+        }
+        (_, 2, 3) => ("OUT", vec![]),
+        (_, 3, 3) => ("IN", vec![]),
+        (_, 4, 3) => ("XTHL", vec![]),
+        (_, 5, 3) => ("XCHG", vec![]),
+        (_, 6, 3) => ("DI", vec![]),
+        (_, _, 3) => ("EI", vec![]),
+        (_, _, 4) => (CALLS[ddd], vec![]),
+        (_, _, 5) if ddd & 1 == 0 => ("PUSH", vec![Operand::Register(STACK_PAIRS[rp])]),
+        (_, 1, 5) => ("CALL", vec![]),
+        (_, _, 5) => {
+            undocumented = true;
+            ("CALL", vec![])
+        }
+        (_, _, 6) => (ALU_IMMEDIATE[ddd], vec![]),
+        (_, _, _) => ("RST", vec![Operand::Restart(ddd as u8)]),
+    };
+
+    // The length comes from the core so the listing always steps through
+    // memory the way the cpu does
+    let length = instruction_length(opcode);
+    let immediate = match length {
+        2 => Some(Operand::Byte(read(1))),
+        3 => Some(Operand::Word((read(2) as u16) << 8 | read(1) as u16)),
+        _ => None,
+    };
+    operands.extend(immediate);
+
+    // Every three byte instruction in the top group is a jump or a call
+    let target = match (opcode >> 6, immediate) {
+        (3, Some(Operand::Word(address))) => Some(address),
+        _ if mnemonic == "RST" => Some((ddd as u16) << 3),
+        _ => None,
+    };
+
+    Instruction {
+        address,
+        bytes: (0..length).map(read).collect(),
+        mnemonic,
+        operands,
+        target,
+        undocumented,
+    }
+}
+
+// Decodes instructions one after another through a range of memory
+pub struct Instructions<'a> {
+    memory: &'a [u8],
+    address: usize,
+    end: usize,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Instruction> {
+        if self.address >= self.end {
+            return None;
         }
+        let instruction = decode(self.memory, self.address as u16);
+        self.address += instruction.length() as usize;
+        Some(instruction)
     }
+}
 
-    cursor
+// Disassembles every instruction starting inside `range`, which can run up
+// to 0x10000 to cover the whole address space
+pub fn disassemble(memory: &[u8], range: Range<usize>) -> Instructions<'_> {
+    Instructions {
+        memory,
+        address: range.start,
+        end: range.end,
+    }
+}
+
+// Prints the instruction at `cur` and returns the cursor of its last byte
+#[allow(dead_code)]
+pub fn get_single(buf: &[u8], cur: usize) -> usize {
+    let instruction = decode(buf, cur as u16);
+    println!("{}", instruction);
+    cur + instruction.length() as usize - 1
 }
 
 #[allow(dead_code)]
-pub fn print_all(buf: &Vec<u8>) {
+pub fn print_all(buf: &[u8]) {
     println!("start disassembly");
     let mut cursor: usize = 0;
 
     while cursor < buf.len() {
         print!("{:04x} ", cursor);
 
-        cursor = get_single(&buf, cursor);
+        cursor = get_single(buf, cursor);
 
         cursor += 1;
     }
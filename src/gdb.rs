@@ -0,0 +1,427 @@
+// GDB remote serial protocol stub, so an external debugger can attach to the
+// emulator over a local TCP port. It supports reading and writing registers
+// and memory, software breakpoints, watchpoints, single stepping and
+// continuing, which is all a front end needs to drive the 8080.
+//
+// GDB has no 8080 of its own, so the registers are described to it in
+// target.xml as A, the flags, BC, DE, HL, SP and PC, in that order. The pairs
+// are sent little endian like any other 16 bit register.
+use crate::debugger::Target;
+use crate::intel8080_state::StateIntel8080;
+use crate::watch::{Access, Hit, Watch};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.i8080.core">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="f" bitsize="8" type="uint8"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Bytes in each register, in the order target.xml gives them
+const REGISTER_SIZES: [usize; 7] = [1, 1, 2, 2, 2, 2, 2];
+
+// Cycles run between checks for GDB interrupting a continue
+const SLICE: u64 = 10_000;
+
+// SIGTRAP, the signal GDB expects for breakpoints and steps
+const SIGTRAP: &str = "S05";
+
+#[derive(Debug, Default)]
+pub struct GdbStub {
+    // Set by c, the server keeps calling resume until it stops
+    pub running: bool,
+    // Set by k and D, the connection is finished with
+    pub finished: bool,
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        Default::default()
+    }
+
+    // Handles one packet's contents. Returns the reply, or None after a
+    // continue, whose reply comes from resume once the target stops.
+    pub fn packet<T: Target>(&mut self, target: &mut T, packet: &str) -> Option<String> {
+        let state = &mut target.cpu().state;
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => SIGTRAP.to_string(),
+            "g" => (0..REGISTER_SIZES.len())
+                .map(|register| register_hex(state, register))
+                .collect(),
+            "G" => {
+                let mut rest = arguments;
+                for (register, size) in REGISTER_SIZES.iter().enumerate() {
+                    match little_endian(rest.get(..size * 2).unwrap_or("")) {
+                        Some(value) => set_register(state, register, value),
+                        None => return Some("E01".to_string()),
+                    }
+                    rest = &rest[size * 2..];
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(arguments, 16) {
+                Ok(register) if register < REGISTER_SIZES.len() => register_hex(state, register),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let mut fields = arguments.splitn(2, '=');
+                let register = fields
+                    .next()
+                    .and_then(|r| usize::from_str_radix(r, 16).ok());
+                let value = fields.next().and_then(little_endian);
+                match (register, value) {
+                    (Some(register), Some(value)) if register < REGISTER_SIZES.len() => {
+                        set_register(state, register, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match address_length(arguments) {
+                Some((address, length)) => (0..length)
+                    .map(|offset| format!("{:02x}", state.peek(address.wrapping_add(offset))))
+                    .collect(),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let mut fields = arguments.splitn(2, ':');
+                let range = fields.next().and_then(address_length);
+                let bytes = fields.next().and_then(hex_bytes);
+                match (range, bytes) {
+                    (Some((address, length)), Some(bytes)) if bytes.len() == length as usize => {
+                        for (offset, byte) in bytes.into_iter().enumerate() {
+                            state.poke(address.wrapping_add(offset as u16), byte);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => self.breakpoint(target, command == "Z", arguments),
+            "s" | "c" => {
+                if let Ok(address) = u16::from_str_radix(arguments, 16) {
+                    state.pc = address;
+                }
+                if command == "c" {
+                    self.running = true;
+                    return None;
+                }
+                let next = target.cpu().cycles + 1;
+                target.run_until(next);
+                stop_reply(target)
+            }
+            "q" => query(arguments),
+            "H" => "OK".to_string(),
+            "D" => {
+                self.finished = true;
+                "OK".to_string()
+            }
+            "k" => {
+                self.finished = true;
+                return None;
+            }
+            // An empty reply tells GDB the packet isn't supported
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    // Runs a continued target a little further, returning the stop reply if
+    // it hit a breakpoint or watchpoint
+    pub fn resume<T: Target>(&mut self, target: &mut T) -> Option<String> {
+        let slice_end = target.cpu().cycles + SLICE;
+        if !self.running || target.run_until(slice_end) {
+            return None;
+        }
+        self.running = false;
+        Some(stop_reply(target))
+    }
+
+    // GDB sent a break while the target was running
+    pub fn interrupt<T: Target>(&mut self, target: &mut T) -> String {
+        self.running = false;
+        target.cpu().state.watchpoints.hit = None;
+        "S02".to_string()
+    }
+
+    // Z0 and Z1 are breakpoints, Z2, Z3 and Z4 write, read and access
+    // watchpoints
+    fn breakpoint<T: Target>(&mut self, target: &mut T, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let kind = fields.next().unwrap_or("");
+        let address = fields.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+        let length = fields
+            .next()
+            .and_then(|l| u16::from_str_radix(l, 16).ok())
+            .unwrap_or(1)
+            .max(1);
+        let address = match address {
+            Some(address) => address,
+            None => return "E01".to_string(),
+        };
+        let cpu = target.cpu();
+
+        let access = match kind {
+            "0" | "1" => {
+                if insert {
+                    cpu.breakpoints.insert(address);
+                } else {
+                    cpu.breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::Both,
+            _ => return String::new(),
+        };
+        let watch = Watch::Memory {
+            start: address,
+            end: address.saturating_add(length - 1),
+            access,
+        };
+        let watches = &mut cpu.state.watchpoints.watches;
+        if insert {
+            watches.push(watch);
+        } else if let Some(index) = watches.iter().position(|w| *w == watch) {
+            watches.remove(index);
+        }
+        "OK".to_string()
+    }
+}
+
+// Serves one GDB connection on `listener` until it kills or detaches
+pub fn serve<T: Target>(target: &mut T, listener: TcpListener) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    let mut connection = Connection {
+        stream,
+        buffer: Vec::new(),
+    };
+    let mut stub = GdbStub::new();
+
+    while !stub.finished {
+        if stub.running {
+            let reply = if connection.interrupted()? {
+                Some(stub.interrupt(target))
+            } else {
+                stub.resume(target)
+            };
+            if let Some(reply) = reply {
+                connection.send(&reply)?;
+            }
+            continue;
+        }
+        match connection.receive()? {
+            Some(packet) => {
+                if let Some(reply) = stub.packet(target, &packet) {
+                    connection.send(&reply)?;
+                }
+            }
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+// Packets framed as $data#checksum on the socket
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    // Waits for the next whole packet, None once GDB has gone
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            if let Some(packet) = self.take_packet()? {
+                return Ok(Some(packet));
+            }
+            let mut bytes = [0; 1024];
+            match self.stream.read(&mut bytes)? {
+                0 => return Ok(None),
+                read => self.buffer.extend_from_slice(&bytes[..read]),
+            }
+        }
+    }
+
+    // Whether GDB has sent a break (^C) since the target started running
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut bytes = [0; 1024];
+        let read = self.stream.read(&mut bytes);
+        self.stream.set_nonblocking(false)?;
+        match read {
+            Ok(read) => self.buffer.extend_from_slice(&bytes[..read]),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+            Err(error) => return Err(error),
+        }
+        match self.buffer.iter().position(|&byte| byte == 0x03) {
+            Some(index) => {
+                self.buffer.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Pulls a packet out of the buffer, acknowledging it, or asks for it
+    // again if the checksum is wrong
+    fn take_packet(&mut self) -> io::Result<Option<String>> {
+        // Acks for our packets and stray breaks are dropped
+        let start = match self.buffer.iter().position(|&byte| byte == b'$') {
+            Some(start) => start,
+            None => {
+                self.buffer.clear();
+                return Ok(None);
+            }
+        };
+        let end = match self.buffer[start..].iter().position(|&byte| byte == b'#') {
+            Some(end) if start + end + 3 <= self.buffer.len() => start + end,
+            _ => return Ok(None),
+        };
+        let data = self.buffer[start + 1..end].to_vec();
+        let sent = String::from_utf8_lossy(&self.buffer[end + 1..end + 3]).to_string();
+        self.buffer.drain(..end + 3);
+
+        if u8::from_str_radix(&sent, 16).ok() != Some(checksum(&data)) {
+            self.stream.write_all(b"-")?;
+            return Ok(None);
+        }
+        self.stream.write_all(b"+")?;
+        Ok(Some(String::from_utf8_lossy(&data).to_string()))
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, byte| sum.wrapping_add(*byte))
+}
+
+// Why the target stopped, naming the address for a watchpoint
+fn stop_reply<T: Target>(target: &mut T) -> String {
+    match target.cpu().state.watchpoints.hit.take() {
+        Some(Hit::Read { address, .. }) => format!("T05rwatch:{:04x};", address),
+        Some(Hit::Write { address, .. }) => format!("T05watch:{:04x};", address),
+        _ => SIGTRAP.to_string(),
+    }
+}
+
+// General queries, the register description is the one that matters
+fn query(query: &str) -> String {
+    if query.starts_with("Supported") {
+        return "PacketSize=1000;qXfer:features:read+".to_string();
+    }
+    if query == "Attached" {
+        return "1".to_string();
+    }
+    if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+        let mut fields = range.split(',');
+        let offset = fields
+            .next()
+            .and_then(|o| usize::from_str_radix(o, 16).ok());
+        let length = fields
+            .next()
+            .and_then(|l| usize::from_str_radix(l, 16).ok());
+        return match (offset, length) {
+            (Some(offset), Some(length)) => {
+                let offset = offset.min(TARGET_XML.len());
+                let end = (offset + length).min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+                format!("{}{}", more, &TARGET_XML[offset..end])
+            }
+            _ => "E01".to_string(),
+        };
+    }
+    String::new()
+}
+
+fn register(state: &StateIntel8080, register: usize) -> u16 {
+    let pair = |high: u8, low: u8| (high as u16) << 8 | low as u16;
+    match register {
+        0 => state.a as u16,
+        1 => state.condition.to_psw() as u16,
+        2 => pair(state.b, state.c),
+        3 => pair(state.d, state.e),
+        4 => pair(state.h, state.l),
+        5 => state.sp,
+        _ => state.pc,
+    }
+}
+
+fn set_register(state: &mut StateIntel8080, register: usize, value: u16) {
+    let (high, low) = ((value >> 8) as u8, value as u8);
+    match register {
+        0 => state.a = low,
+        1 => state.condition.set_psw(low),
+        2 => {
+            state.b = high;
+            state.c = low;
+        }
+        3 => {
+            state.d = high;
+            state.e = low;
+        }
+        4 => {
+            state.h = high;
+            state.l = low;
+        }
+        5 => state.sp = value,
+        _ => state.pc = value,
+    }
+}
+
+// A register as GDB wants it, little endian hex
+fn register_hex(state: &StateIntel8080, number: usize) -> String {
+    let value = register(state, number);
+    (0..REGISTER_SIZES[number])
+        .map(|byte| format!("{:02x}", (value >> (byte * 8)) as u8))
+        .collect()
+}
+
+fn little_endian(hex: &str) -> Option<u16> {
+    let bytes = hex_bytes(hex)?;
+    if bytes.is_empty() || bytes.len() > 2 {
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u16),
+    )
+}
+
+// Pairs of hex digits, None if there's an odd one out
+fn hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+// addr,length for m and M
+fn address_length(arguments: &str) -> Option<(u16, u16)> {
+    let mut fields = arguments.split(',');
+    let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+    let length = u16::from_str_radix(fields.next()?, 16).ok()?;
+    Some((address, length))
+}
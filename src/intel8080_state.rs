@@ -1,7 +1,8 @@
+use crate::bus::{Bus, MemoryMap};
 use crate::condition_codes::ConditionCodes;
+use crate::watch::Watchpoints;
 
 // Created using http://www.emulator101.com/emulator-shell.html as a resource
-// Output/Input guide from https://github.com/dramikei/rust_8080
 #[derive(Debug, Default)]
 pub struct StateIntel8080 {
     pub a: u8,
@@ -15,28 +16,28 @@ pub struct StateIntel8080 {
     pub pc: u16,
     pub condition: ConditionCodes,
     pub memory: Vec<u8>,
-
-    // External hardware shifts
-    pub shift_0: u8,
-    pub shift_1: u8,
-    pub shift_offset: u8,
-    pub input_1: u8,
-    pub input_2: u8,
+    // How addresses decode onto memory, defaults to a flat 64KB of RAM
+    pub memory_map: MemoryMap,
 
     // Interrupt Booleans
+    // Interrupt enable flip-flop, set by EI and cleared by DI or taking one
     pub interrupts: bool,
+    // EI waits for the next instruction to finish before letting one in
+    pub interrupt_delay: bool,
+    // Instruction a device placed on the bus when it interrupted, held until
+    // interrupts are enabled
+    pub pending_interrupt: Option<[u8; 3]>,
+    // Set by HLT, only an interrupt gets the cpu going again
+    pub halted: bool,
 
-    // Output ports for sounds
-    pub output_3: u8,
-    pub output_5: u8,
-    pub last_output_3: u8,
-    pub last_output_5: u8,
+    // Memory and port accesses the debugger is waiting for
+    pub watchpoints: Watchpoints,
 }
 
 impl StateIntel8080 {
-    pub fn init_mem(&mut self, buf: &Vec<u8>) {
+    pub fn init_mem(&mut self, buf: &[u8]) {
         // intel 8080 has a maximum memory of 64KB
-        self.memory = vec![0; 0xffff];
+        self.memory = vec![0; 0x10000];
         let mut i = 0;
         while i < buf.len() {
             self.memory[i] = buf[i];
@@ -45,15 +46,60 @@ impl StateIntel8080 {
         // self.memory = vec![0; 0x4000];
     }
 
+    // Reads memory as the cpu would see it, for tools that shouldn't disturb
+    // anything by looking
+    pub fn peek(&self, address: u16) -> u8 {
+        self.memory_map.read(&self.memory, address)
+    }
+
+    // Writes memory for a debugger or patch. Unlike the cpu this can change
+    // ROM, only unmapped space is left alone.
+    pub fn poke(&mut self, address: u16, value: u8) {
+        if let Some((backing, _)) = self.memory_map.resolve(address) {
+            self.memory[backing as usize] = value;
+        }
+    }
+
+    // Raises RST n, the usual way a device interrupts the 8080
     pub fn generate_interrupt(&mut self, interrupt_num: u8) {
-        if self.interrupts {
-            self.memory[self.sp as usize - 1] = (self.pc >> 8) as u8;
-            self.memory[self.sp as usize - 2] = self.pc as u8;
-            self.sp -= 2;
+        self.request_interrupt(&[0xc7 | (interrupt_num << 3)]);
+    }
 
-            self.pc = (8 * interrupt_num) as u16;
+    // Latches an interrupt with the instruction the device drives onto the
+    // bus, any opcode works and CALL can supply its address too. A newer
+    // request replaces one that hasn't been taken yet.
+    pub fn request_interrupt(&mut self, instruction: &[u8]) {
+        let mut latched = [0; 3];
+        for (byte, value) in latched.iter_mut().zip(instruction) {
+            *byte = *value;
         }
+        self.pending_interrupt = Some(latched);
+    }
 
+    // Takes the pending interrupt if interrupts are enabled, clearing the
+    // enable flip-flop and waking the cpu from HLT like the 8080 does
+    pub fn acknowledge_interrupt(&mut self, delayed: bool) -> Option<[u8; 3]> {
+        if !self.interrupts || delayed {
+            return None;
+        }
+        let instruction = self.pending_interrupt.take()?;
         self.interrupts = false;
+        self.halted = false;
+        Some(instruction)
+    }
+}
+
+// All cpu memory accesses are decoded through the memory map, and checked
+// against the watchpoints
+impl Bus for StateIntel8080 {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.memory_map.read(&self.memory, address);
+        self.watchpoints.read(address, value);
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.watchpoints.write(address, value);
+        self.memory_map.write(&mut self.memory, address, value);
     }
 }
@@ -0,0 +1,417 @@
+// Space Invaders machine definition
+// Memory layout from http://computerarcheology.com/Arcade/SpaceInvaders/Hardware.html
+use crate::bus::MemoryMap;
+use crate::cpu::Cpu;
+use crate::io::{IoBus, PortDevice, UNCONNECTED};
+use crate::save_state::{Reader, Writer};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Start and end (exclusive) of the video RAM, one bit per pixel
+pub const VRAM_START: usize = 0x2400;
+pub const VRAM_END: usize = 0x4000;
+
+// The cpu clock is the 19.968MHz crystal divided by 10
+pub const CLOCK_RATE: u64 = 1_996_800;
+// The screen refreshes at 60hz
+pub const CYCLES_PER_FRAME: u64 = CLOCK_RATE / 60;
+
+// The beam draws 224 visible lines, then spends the rest of the frame in
+// vertical blank. The game gets RST 1 when the beam reaches the middle of
+// the screen and RST 2 when it enters VBLANK, and redraws whichever half
+// the beam isn't on.
+pub const VISIBLE_LINES: u64 = 224;
+pub const TOTAL_LINES: u64 = 262;
+pub const MID_SCREEN_LINE: u64 = 96;
+pub const VBLANK_LINE: u64 = 224;
+// Each visible line is 256 pixels, one bit each
+pub const LINE_BYTES: usize = 32;
+
+// 8KB of ROM, 1KB of work RAM followed by 7KB of video RAM. The upper
+// address lines aren't decoded, so everything above 0x4000 repeats the
+// first 16KB.
+pub fn memory_map() -> MemoryMap {
+    let mut map = MemoryMap::new();
+    map.rom(0x0000, 0x1fff)
+        .ram(0x2000, 0x3fff)
+        .mirror(0x4000, 0xffff, 0x0000, 0x4000);
+    map
+}
+
+// Input ports 0-2, bits are set and cleared by the frontend from the keyboard
+#[derive(Debug)]
+pub struct Inputs {
+    pub input_0: u8,
+    pub input_1: u8,
+    pub input_2: u8,
+}
+
+impl Default for Inputs {
+    fn default() -> Inputs {
+        Inputs {
+            input_0: 0xf,
+            input_1: 0,
+            input_2: 0,
+        }
+    }
+}
+
+impl PortDevice for Inputs {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            0 => self.input_0,
+            1 => self.input_1,
+            2 => self.input_2,
+            _ => UNCONNECTED,
+        }
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+}
+
+// External hardware shift register, the 8080 has no barrel shifter so the
+// game uses this to draw sprites at any x position
+// OUT 2 sets the shift amount, OUT 4 shifts data in, IN 3 reads the result
+#[derive(Debug, Default)]
+pub struct ShiftRegister {
+    pub shift_0: u8,
+    pub shift_1: u8,
+    pub shift_offset: u8,
+}
+
+impl PortDevice for ShiftRegister {
+    fn input(&mut self, _port: u8) -> u8 {
+        let visual = ((self.shift_1 as u16) << 8) | (self.shift_0 as u16);
+        (visual >> (8 - (self.shift_offset as u16))) as u8
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            2 => self.shift_offset = value & 0x7,
+            _ => {
+                self.shift_0 = self.shift_1;
+                self.shift_1 = value;
+            }
+        }
+    }
+}
+
+// Output ports 3 and 5 latch which sounds are playing, the frontend
+// compares them against the last values it played
+#[derive(Debug, Default)]
+pub struct SoundLatches {
+    pub output_3: u8,
+    pub output_5: u8,
+    pub last_output_3: u8,
+    pub last_output_5: u8,
+}
+
+impl PortDevice for SoundLatches {
+    fn input(&mut self, _port: u8) -> u8 {
+        UNCONNECTED
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            3 => self.output_3 = value,
+            _ => self.output_5 = value,
+        }
+    }
+}
+
+// OUT 6 resets the watchdog timer, nothing resets the machine here so
+// it only counts the kicks
+#[derive(Debug, Default)]
+pub struct Watchdog {
+    pub kicks: u32,
+}
+
+impl PortDevice for Watchdog {
+    fn input(&mut self, _port: u8) -> u8 {
+        UNCONNECTED
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {
+        self.kicks = self.kicks.wrapping_add(1);
+    }
+}
+
+// Handles to the devices the frontend needs while the game runs, clones
+// share the same devices
+#[derive(Debug, Default, Clone)]
+pub struct Devices {
+    pub inputs: Rc<RefCell<Inputs>>,
+    pub shift_register: Rc<RefCell<ShiftRegister>>,
+    pub sound: Rc<RefCell<SoundLatches>>,
+    pub watchdog: Rc<RefCell<Watchdog>>,
+}
+
+// Attaches the Space Invaders devices to their ports
+pub fn attach_devices(io: &mut IoBus) -> Devices {
+    let devices: Devices = Default::default();
+    io.attach(Box::new(devices.inputs.clone()), &[0, 1, 2], &[]);
+    io.attach(Box::new(devices.shift_register.clone()), &[3], &[2, 4]);
+    io.attach(Box::new(devices.sound.clone()), &[], &[3, 5]);
+    io.attach(Box::new(devices.watchdog.clone()), &[], &[6]);
+    devices
+}
+
+// Builds a cpu wired up as a Space Invaders machine
+pub fn new_cpu(rom: Vec<u8>) -> (Cpu, Devices) {
+    let mut cpu = Cpu::new(rom);
+    cpu.state.memory_map = memory_map();
+    let devices = attach_devices(&mut cpu.io);
+    (cpu, devices)
+}
+
+// Cycle the beam starts drawing `line` on, for the frame starting at
+// `frame_start`. Scaled from the whole frame so the lines add up exactly.
+pub fn line_start(frame_start: u64, line: u64) -> u64 {
+    frame_start + line * CYCLES_PER_FRAME / TOTAL_LINES
+}
+
+// Where the beam is in the frame. Running the machine through it raises the
+// video interrupts as the beam reaches their lines and calls back with each
+// visible line once the cpu has run through it, so the screen can be
+// rasterized in step with the game. It keeps its place when the cpu stops at
+// a breakpoint part way through a frame.
+#[derive(Debug, Default)]
+pub struct Beam {
+    // Cycle the current frame started on
+    pub frame_start: u64,
+    pub line: u64,
+}
+
+impl Beam {
+    // Runs the rest of the current frame, false if it stopped at a breakpoint
+    pub fn run_frame<F>(&mut self, cpu: &mut Cpu, scanline: F) -> bool
+    where
+        F: FnMut(&Cpu, usize),
+    {
+        let frame_end = self.frame_start + CYCLES_PER_FRAME;
+        self.run_until(cpu, frame_end, scanline)
+    }
+
+    // Runs until the cpu's cycle total reaches `cycle`, false if it stopped at
+    // a breakpoint first
+    pub fn run_until<F>(&mut self, cpu: &mut Cpu, cycle: u64, mut scanline: F) -> bool
+    where
+        F: FnMut(&Cpu, usize),
+    {
+        loop {
+            let line_end = line_start(self.frame_start, self.line + 1);
+            let finished = cpu.run_until(line_end.min(cycle));
+            if cpu.cycles >= line_end {
+                self.next_line(cpu, &mut scanline);
+            }
+            if !finished {
+                return false;
+            }
+            if cpu.cycles >= cycle {
+                return true;
+            }
+        }
+    }
+
+    // The beam has finished its line, moves on to the next
+    fn next_line<F>(&mut self, cpu: &mut Cpu, scanline: &mut F)
+    where
+        F: FnMut(&Cpu, usize),
+    {
+        if self.line < VISIBLE_LINES {
+            scanline(cpu, self.line as usize);
+        }
+        self.line += 1;
+        if self.line == TOTAL_LINES {
+            self.line = 0;
+            self.frame_start += CYCLES_PER_FRAME;
+        }
+
+        if self.line == MID_SCREEN_LINE {
+            cpu.state.generate_interrupt(1);
+        }
+        if self.line == VBLANK_LINE {
+            cpu.state.generate_interrupt(2);
+        }
+    }
+}
+
+// Video RAM for one visible line
+pub fn vram_line(memory: &[u8], line: usize) -> &[u8] {
+    let start = VRAM_START + line * LINE_BYTES;
+    &memory[start..start + LINE_BYTES]
+}
+
+// Name save states from this machine are marked with
+pub const MACHINE: &str = "invaders";
+
+// Saves the whole machine: the cpu, the device latches and where the beam is,
+// so the interrupts keep coming on the same cycles after loading
+pub fn save_state(cpu: &Cpu, devices: &Devices, beam: &Beam) -> Vec<u8> {
+    let mut writer = Writer::new(MACHINE);
+    cpu.save(&mut writer);
+
+    let shift_register = devices.shift_register.borrow();
+    writer.u8(shift_register.shift_0);
+    writer.u8(shift_register.shift_1);
+    writer.u8(shift_register.shift_offset);
+    let inputs = devices.inputs.borrow();
+    writer.u8(inputs.input_0);
+    writer.u8(inputs.input_1);
+    writer.u8(inputs.input_2);
+    let sound = devices.sound.borrow();
+    writer.u8(sound.output_3);
+    writer.u8(sound.output_5);
+    writer.u8(sound.last_output_3);
+    writer.u8(sound.last_output_5);
+
+    writer.u64(beam.frame_start);
+    writer.u64(beam.line);
+    writer.bytes
+}
+
+// Loads a state from save_state. It's read into a spare machine first, so
+// one that can't be read leaves this one as it was.
+pub fn load_state(
+    bytes: &[u8],
+    cpu: &mut Cpu,
+    devices: &Devices,
+    beam: &mut Beam,
+) -> Result<(), String> {
+    let (mut spare, spare_devices) = new_cpu(Vec::new());
+    read_state(bytes, &mut spare, &spare_devices, &mut Beam::default())?;
+    read_state(bytes, cpu, devices, beam)
+}
+
+fn read_state(
+    bytes: &[u8],
+    cpu: &mut Cpu,
+    devices: &Devices,
+    beam: &mut Beam,
+) -> Result<(), String> {
+    let mut reader = Reader::new(bytes, MACHINE)?;
+    cpu.load(&mut reader)?;
+
+    let mut shift_register = devices.shift_register.borrow_mut();
+    shift_register.shift_0 = reader.u8()?;
+    shift_register.shift_1 = reader.u8()?;
+    shift_register.shift_offset = reader.u8()? & 0x7;
+    let mut inputs = devices.inputs.borrow_mut();
+    inputs.input_0 = reader.u8()?;
+    inputs.input_1 = reader.u8()?;
+    inputs.input_2 = reader.u8()?;
+    let mut sound = devices.sound.borrow_mut();
+    sound.output_3 = reader.u8()?;
+    sound.output_5 = reader.u8()?;
+    sound.last_output_3 = reader.u8()?;
+    sound.last_output_5 = reader.u8()?;
+
+    beam.frame_start = reader.u64()?;
+    beam.line = reader.u64()?;
+    if beam.line >= TOTAL_LINES {
+        return Err(format!("save state has the beam on line {}", beam.line));
+    }
+    reader.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shift_register_offsets() {
+        let mut io = IoBus::new();
+        let devices = attach_devices(&mut io);
+        io.output(4, 0xab);
+        io.output(4, 0xcd);
+        // The newest byte is the high one, offset 0 reads it as it was
+        assert_eq!(io.input(3), 0xcd);
+        // Only the low 3 bits of the offset count
+        io.output(2, 0x0f);
+        assert_eq!(devices.shift_register.borrow().shift_offset, 7);
+        assert_eq!(io.input(3), 0xd5);
+        io.output(2, 4);
+        assert_eq!(io.input(3), 0xda);
+    }
+
+    #[test]
+    fn watchdog_counts_kicks() {
+        let mut io = IoBus::new();
+        let devices = attach_devices(&mut io);
+        for _ in 0..3 {
+            io.output(6, 0);
+        }
+        assert_eq!(devices.watchdog.borrow().kicks, 3);
+        devices.watchdog.borrow_mut().kicks = u32::MAX;
+        io.output(6, 0);
+        assert_eq!(devices.watchdog.borrow().kicks, 0);
+    }
+
+    #[test]
+    fn sound_latches_and_inputs() {
+        let mut io = IoBus::new();
+        let devices = attach_devices(&mut io);
+        io.output(3, 0x02);
+        io.output(5, 0x10);
+        let sound = devices.sound.borrow();
+        assert_eq!((sound.output_3, sound.output_5), (0x02, 0x10));
+        assert_eq!(io.input(0), 0x0f);
+        devices.inputs.borrow_mut().input_1 = 0x04;
+        assert_eq!(io.input(1), 0x04);
+        assert_eq!(io.input(7), UNCONNECTED);
+    }
+
+    // Runs NOPs with interrupts off, so the beam's requests stay latched
+    fn nops() -> (Cpu, Beam) {
+        let (cpu, _) = new_cpu(vec![0; 0x2000]);
+        (cpu, Beam::default())
+    }
+
+    #[test]
+    fn interrupts_on_their_lines() {
+        let (mut cpu, mut beam) = nops();
+        let mut run_to = |line: u64| {
+            assert!(beam.run_until(&mut cpu, line_start(0, line), |_, _| {}));
+            assert_eq!(beam.line, line);
+            cpu.state.pending_interrupt.take()
+        };
+        assert_eq!(run_to(MID_SCREEN_LINE - 1), None);
+        assert_eq!(run_to(MID_SCREEN_LINE), Some([0xcf, 0, 0]));
+        assert_eq!(run_to(VBLANK_LINE - 1), None);
+        assert_eq!(run_to(VBLANK_LINE), Some([0xd7, 0, 0]));
+        assert_eq!(run_to(TOTAL_LINES - 1), None);
+    }
+
+    #[test]
+    fn frames_are_262_lines() {
+        let (mut cpu, mut beam) = nops();
+        for frame in 1..=2 {
+            let mut lines = Vec::new();
+            assert!(beam.run_frame(&mut cpu, |_, line| lines.push(line)));
+            assert_eq!(lines, (0..VISIBLE_LINES as usize).collect::<Vec<_>>());
+            assert_eq!(beam.frame_start, frame * CYCLES_PER_FRAME);
+            assert_eq!(beam.line, 0);
+            assert!(cpu.cycles >= beam.frame_start);
+            assert!(cpu.cycles < line_start(beam.frame_start, 1));
+        }
+    }
+
+    #[test]
+    fn keeps_its_place_at_a_breakpoint() {
+        let (mut cpu, mut beam) = nops();
+        cpu.breakpoints.insert(0x1000);
+        let mut lines = Vec::new();
+        assert!(!beam.run_frame(&mut cpu, |_, line| lines.push(line)));
+        assert_eq!(cpu.state.pc, 0x1000);
+        let stopped = beam.line;
+        assert!(stopped > MID_SCREEN_LINE && stopped < VBLANK_LINE);
+        assert_eq!(beam.frame_start, 0);
+        assert_eq!(lines.len() as u64, stopped);
+
+        // Carries on with the same frame, every line drawn once
+        cpu.breakpoints.clear();
+        assert!(beam.run_frame(&mut cpu, |_, line| lines.push(line)));
+        assert_eq!(lines, (0..VISIBLE_LINES as usize).collect::<Vec<_>>());
+        assert_eq!(beam.frame_start, CYCLES_PER_FRAME);
+    }
+}
@@ -0,0 +1,147 @@
+// I/O ports, each machine attaches its own devices to the port numbers
+// the hardware decodes instead of the cpu knowing about them
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+pub trait PortDevice {
+    // IN instruction, the value the device drives onto the data bus
+    fn input(&mut self, port: u8) -> u8;
+    // OUT instruction, the accumulator written to the device
+    fn output(&mut self, port: u8, value: u8);
+}
+
+// Lets a frontend keep a handle on a device after attaching it, e.g. to
+// set the input ports from the keyboard
+impl<T: PortDevice> PortDevice for Rc<RefCell<T>> {
+    fn input(&mut self, port: u8) -> u8 {
+        self.borrow_mut().input(port)
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        self.borrow_mut().output(port, value)
+    }
+}
+
+// Value read from a port nothing is attached to
+pub const UNCONNECTED: u8 = 0;
+
+// Routes IN/OUT to the device registered on each port
+#[derive(Default)]
+pub struct IoBus {
+    devices: Vec<Box<dyn PortDevice>>,
+    inputs: HashMap<u8, usize>,
+    outputs: HashMap<u8, usize>,
+}
+
+impl IoBus {
+    pub fn new() -> IoBus {
+        Default::default()
+    }
+
+    // Attaches a device to the given input and output ports, a port can only
+    // belong to one device so a later attach takes it over
+    pub fn attach(&mut self, device: Box<dyn PortDevice>, in_ports: &[u8], out_ports: &[u8]) {
+        let index = self.devices.len();
+        self.devices.push(device);
+        for port in in_ports {
+            self.inputs.insert(*port, index);
+        }
+        for port in out_ports {
+            self.outputs.insert(*port, index);
+        }
+    }
+
+    pub fn input(&mut self, port: u8) -> u8 {
+        match self.inputs.get(&port) {
+            Some(index) => self.devices[*index].input(port),
+            None => UNCONNECTED,
+        }
+    }
+
+    // Writes to ports nothing is attached to are dropped
+    pub fn output(&mut self, port: u8, value: u8) {
+        if let Some(index) = self.outputs.get(&port) {
+            self.devices[*index].output(port, value);
+        }
+    }
+}
+
+impl fmt::Debug for IoBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut inputs: Vec<&u8> = self.inputs.keys().collect();
+        let mut outputs: Vec<&u8> = self.outputs.keys().collect();
+        inputs.sort();
+        outputs.sort();
+        f.debug_struct("IoBus")
+            .field("devices", &self.devices.len())
+            .field("inputs", &inputs)
+            .field("outputs", &outputs)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Remembers what was written, reads back the port number plus the last
+    // value
+    #[derive(Debug, Default)]
+    struct Latch {
+        written: Vec<(u8, u8)>,
+    }
+
+    impl PortDevice for Latch {
+        fn input(&mut self, port: u8) -> u8 {
+            port + self.written.last().map_or(0, |(_, value)| *value)
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.written.push((port, value));
+        }
+    }
+
+    #[test]
+    fn routes_to_the_attached_device() {
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        let other = Rc::new(RefCell::new(Latch::default()));
+        let mut io = IoBus::new();
+        // One device reading on one port and written on others
+        io.attach(Box::new(latch.clone()), &[1], &[2, 4]);
+        io.attach(Box::new(other.clone()), &[3], &[3]);
+
+        io.output(2, 0x10);
+        io.output(4, 0x20);
+        io.output(3, 0x30);
+        assert_eq!(latch.borrow().written, [(2, 0x10), (4, 0x20)]);
+        assert_eq!(other.borrow().written, [(3, 0x30)]);
+        assert_eq!(io.input(1), 0x21);
+        assert_eq!(io.input(3), 0x33);
+    }
+
+    #[test]
+    fn unconnected_ports() {
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        let mut io = IoBus::new();
+        io.attach(Box::new(latch.clone()), &[1], &[2]);
+        // Reading the output port or writing the input port reaches nothing
+        assert_eq!(io.input(2), UNCONNECTED);
+        assert_eq!(io.input(0xff), UNCONNECTED);
+        io.output(1, 0x55);
+        assert!(latch.borrow().written.is_empty());
+    }
+
+    #[test]
+    fn later_attach_takes_the_port() {
+        let first = Rc::new(RefCell::new(Latch::default()));
+        let second = Rc::new(RefCell::new(Latch::default()));
+        let mut io = IoBus::new();
+        io.attach(Box::new(first.clone()), &[], &[5]);
+        io.attach(Box::new(second.clone()), &[], &[5]);
+        io.output(5, 1);
+        assert!(first.borrow().written.is_empty());
+        assert_eq!(second.borrow().written, [(5, 1)]);
+    }
+}
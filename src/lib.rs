@@ -0,0 +1,23 @@
+// Intel 8080 core, usable without the Space Invaders frontend
+pub mod assembler;
+pub mod bus;
+pub mod condition_codes;
+pub mod cpm;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod gdb;
+pub mod intel8080_state;
+pub mod invaders;
+pub mod io;
+pub mod listing;
+pub mod rewind;
+pub mod save_state;
+pub mod symbols;
+pub mod trace;
+pub mod trace_diff;
+pub mod watch;
+
+pub use condition_codes::ConditionCodes;
+pub use cpu::Cpu;
+pub use intel8080_state::StateIntel8080;
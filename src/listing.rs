@@ -0,0 +1,187 @@
+// Recursive descent disassembly. Rather than sweeping memory from the start,
+// code is found by following jumps and calls from the entry points, so the
+// data tables and text in between are left as data. The listing it produces
+// reassembles to the same bytes.
+use crate::disassembler::{decode, Instruction};
+use crate::symbols::Symbols;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Where an 8080 starts running, the reset vector and the RST entry points
+pub const ENTRY_POINTS: [u16; 8] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+
+// Data bytes put on each DB line
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Debug, Default)]
+pub struct Listing {
+    // Address of the first byte
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    // Instructions reached from the entry points, by address
+    pub code: BTreeMap<u16, Instruction>,
+    // Names for the jump and call targets, and any symbols added. Those that
+    // aren't the address of an instruction are written out as EQUs.
+    pub labels: Symbols,
+}
+
+impl Listing {
+    // Follows the flow of `bytes` loaded at `origin` from each entry point
+    pub fn new(bytes: &[u8], origin: u16, entry_points: &[u16]) -> Listing {
+        let mut listing = Listing {
+            origin,
+            bytes: bytes.to_vec(),
+            ..Default::default()
+        };
+        // Which bytes belong to an instruction that has been decoded
+        let mut claimed = vec![false; bytes.len()];
+        // Each entry point is followed to the end before the next is started,
+        // so earlier ones win when they disagree. Machines rarely use every
+        // RST, and a vector that isn't really code can land in the middle of
+        // instructions the reset code reaches.
+        let mut pending: Vec<u16> = entry_points.iter().rev().copied().collect();
+
+        while let Some(address) = pending.pop() {
+            if listing.code.contains_key(&address) {
+                continue;
+            }
+            let offset = match listing.offset(address) {
+                Some(offset) => offset,
+                None => continue,
+            };
+            let instruction = decode(&bytes[offset..], 0);
+            let end = offset + instruction.length() as usize;
+            // Stop at the end of the image, or where flow would run into the
+            // middle of an instruction that was already found
+            if end > bytes.len() || claimed[offset..end].contains(&true) {
+                continue;
+            }
+            for byte in &mut claimed[offset..end] {
+                *byte = true;
+            }
+            // Jump and call targets are absolute, so decoding from the slice
+            // only needs the address fixing
+            let instruction = Instruction {
+                address,
+                ..instruction
+            };
+
+            if let Some(target) = instruction.target {
+                pending.push(target);
+            }
+            if !ends_flow(&instruction) {
+                pending.push(instruction.next());
+            }
+            listing.code.insert(address, instruction);
+        }
+
+        // Only targets that turned out to be instructions get a label, the
+        // rest stay as numbers
+        let targets: Vec<u16> = listing
+            .code
+            .values()
+            .filter_map(|instruction| instruction.target)
+            .collect();
+        for target in targets {
+            if listing.code.contains_key(&target) {
+                listing.labels.insert(target, &format!("L{:04x}", target));
+            }
+        }
+        listing
+    }
+
+    // Names addresses from a symbol file, in place of the generated labels
+    pub fn add_symbols(&mut self, symbols: &Symbols) {
+        self.labels.extend(symbols);
+    }
+
+    // Whether the byte at `address` is part of an instruction
+    pub fn is_code(&self, address: u16) -> bool {
+        match self.code.range(..=address).next_back() {
+            Some((_, instruction)) => {
+                address.wrapping_sub(instruction.address) < instruction.length()
+            }
+            None => false,
+        }
+    }
+
+    fn offset(&self, address: u16) -> Option<usize> {
+        let offset = address.wrapping_sub(self.origin) as usize;
+        if offset < self.bytes.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    // Source for an instruction, with addresses replaced by their labels
+    fn source(&self, instruction: &Instruction) -> String {
+        // The assembler picks the documented encoding, so undocumented opcodes
+        // are written out as bytes to reassemble the same
+        if instruction.undocumented {
+            return format!("{:<8}{} ; {}", "DB", bytes(&instruction.bytes), instruction);
+        }
+        format!(
+            "{:<8}{}",
+            instruction.mnemonic,
+            instruction.operands_with(&self.labels)
+        )
+        .trim_end()
+        .to_string()
+    }
+}
+
+// Instructions that never carry on to the next one
+fn ends_flow(instruction: &Instruction) -> bool {
+    matches!(instruction.mnemonic, "JMP" | "RET" | "PCHL")
+}
+
+// Pads a label out to the mnemonic column, leaving a space after long ones
+fn field(label: &str) -> String {
+    format!("{:<7} ", label)
+}
+
+fn bytes(values: &[u8]) -> String {
+    let values: Vec<String> = values.iter().map(|value| format!("${:02x}", value)).collect();
+    values.join(",")
+}
+
+// Assembler source, with the address of each line as a comment
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (address, name) in self.labels.iter() {
+            if !self.code.contains_key(&address) {
+                writeln!(f, "{}EQU     ${:04x}", field(name), address)?;
+            }
+        }
+        writeln!(f, "{:8}ORG     ${:04x}", "", self.origin)?;
+
+        let mut offset = 0;
+        while offset < self.bytes.len() {
+            let address = self.origin.wrapping_add(offset as u16);
+            if let Some(instruction) = self.code.get(&address) {
+                let label = match self.labels.name(address) {
+                    Some(label) => field(&format!("{}:", label)),
+                    None => field(""),
+                };
+                let source = format!("{}{}", label, self.source(instruction));
+                writeln!(f, "{:<40}; {:04x}", source, address)?;
+                offset += instruction.length() as usize;
+                continue;
+            }
+
+            // Data runs up to the next instruction
+            let mut end = offset + 1;
+            while end < self.bytes.len()
+                && end - offset < DATA_PER_LINE
+                && !self.is_code(self.origin.wrapping_add(end as u16))
+            {
+                end += 1;
+            }
+            let source = format!("{:8}{:<8}{}", "", "DB", bytes(&self.bytes[offset..end]));
+            writeln!(f, "{:<40}; {:04x}", source, address)?;
+            offset = end;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+// Rewinding. The machine hands over a save state at the start of every frame
+// and the last few seconds of them are kept, to go back through a frame at a
+// time. Only the newest is kept whole. Between one frame and the next little
+// changes besides some VRAM and the stack, so each older state is kept as the
+// bytes that differ from the state after it, XORed and with the runs of zeros
+// squeezed out. A few hundred bytes a frame rather than 64KB.
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+#[derive(Debug, Default)]
+pub struct Rewind {
+    // Most states kept, the newest included
+    pub capacity: usize,
+    latest: Option<Vec<u8>>,
+    // Undoes each state back to the one before it, the oldest at the front
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    // Adds the newest state, dropping the oldest once there are too many
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(delta(&state, &latest));
+        }
+        self.latest = Some(state);
+        while self.deltas.len() >= self.capacity.max(1) {
+            self.deltas.pop_front();
+        }
+    }
+
+    pub fn latest(&self) -> Option<&[u8]> {
+        self.latest.as_deref()
+    }
+
+    // Drops the newest state, making the one before it the newest. False if
+    // there's no state before it.
+    pub fn pop(&mut self) -> bool {
+        match (self.deltas.pop_back(), &mut self.latest) {
+            (Some(delta), Some(latest)) => {
+                *latest = undo(latest, &delta);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // States held, the newest included
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    // Bytes taken up by the states
+    pub fn size(&self) -> usize {
+        let latest = self.latest.as_ref().map_or(0, Vec::len);
+        latest + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+}
+
+// What turns `newer` back into `older`: older's length as a u32, then a u16
+// count of bytes that are the same, a u16 count of bytes that differ and
+// those bytes XORed together, over and over. Bytes past the end of `newer`
+// count as zero.
+fn delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    let xor = |index: usize| older[index] ^ newer.get(index).copied().unwrap_or(0);
+    let mut delta = (older.len() as u32).to_le_bytes().to_vec();
+    let mut index = 0;
+    while index < older.len() {
+        let same = index;
+        while index < older.len() && index - same < 0xffff && xor(index) == 0 {
+            index += 1;
+        }
+        let different = index;
+        while index < older.len() && index - different < 0xffff && xor(index) != 0 {
+            index += 1;
+        }
+        delta.extend_from_slice(&((different - same) as u16).to_le_bytes());
+        delta.extend_from_slice(&((index - different) as u16).to_le_bytes());
+        delta.extend((different..index).map(xor));
+    }
+    delta
+}
+
+fn undo(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let length = u32::from_le_bytes(delta[0..4].try_into().unwrap());
+    let mut older = newer.to_vec();
+    older.resize(length as usize, 0);
+    let mut index = 0;
+    let mut runs = &delta[4..];
+    while runs.len() >= 4 {
+        let same = u16::from_le_bytes([runs[0], runs[1]]) as usize;
+        let different = u16::from_le_bytes([runs[2], runs[3]]) as usize;
+        index += same;
+        for (byte, xor) in older[index..index + different].iter_mut().zip(&runs[4..]) {
+            *byte ^= xor;
+        }
+        index += different;
+        runs = &runs[4 + different..];
+    }
+    older
+}
@@ -0,0 +1,202 @@
+// Save states. A file starts with MAGIC, the format version and the name of
+// the machine that saved it, then the cpu, then whatever the machine adds for
+// its own devices. Numbers are little endian, blocks of memory are preceded
+// by their length.
+//
+// A new field means a new VERSION, and reading older versions is handled in
+// the load functions by checking Reader::version.
+use crate::cpu::Cpu;
+use crate::intel8080_state::StateIntel8080;
+use std::convert::TryInto;
+
+pub const MAGIC: &[u8; 8] = b"I8080SAV";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, Default)]
+pub struct Writer {
+    pub bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(machine: &str) -> Writer {
+        let mut writer = Writer::default();
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.u16(VERSION);
+        writer.block(machine.as_bytes());
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn block(&mut self, bytes: &[u8]) {
+        self.bytes
+            .extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+#[derive(Debug)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    // Version of the format the state was saved in
+    pub version: u16,
+}
+
+impl<'a> Reader<'a> {
+    // Checks the header, refusing states from other machines or a newer
+    // version of the format
+    pub fn new(bytes: &'a [u8], machine: &str) -> Result<Reader<'a>, String> {
+        let bytes = bytes
+            .strip_prefix(&MAGIC[..])
+            .ok_or_else(|| "not a save state".to_string())?;
+        let mut reader = Reader { bytes, version: 0 };
+        reader.version = reader.u16()?;
+        if reader.version == 0 || reader.version > VERSION {
+            return Err(format!(
+                "save state version {} isn't supported",
+                reader.version
+            ));
+        }
+        let saved_by = reader.block()?;
+        if saved_by != machine.as_bytes() {
+            return Err(format!(
+                "save state is for {}, not {}",
+                String::from_utf8_lossy(saved_by),
+                machine
+            ));
+        }
+        Ok(reader)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn block(&mut self) -> Result<&'a [u8], String> {
+        let length = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        self.take(length as usize)
+    }
+
+    // Everything should have been read by the end
+    pub fn finish(&self) -> Result<(), String> {
+        if !self.bytes.is_empty() {
+            return Err("save state has data left over".to_string());
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if length > self.bytes.len() {
+            return Err("save state ends early".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+}
+
+impl StateIntel8080 {
+    // Registers, flags, memory and interrupt state. The memory map belongs to
+    // the machine and the watchpoints to the debugger, so neither is saved.
+    pub fn save(&self, writer: &mut Writer) {
+        for register in [self.a, self.b, self.c, self.d, self.e, self.h, self.l].iter() {
+            writer.u8(*register);
+        }
+        writer.u16(self.sp);
+        writer.u16(self.pc);
+        writer.u8(self.condition.to_psw());
+        writer.bool(self.interrupts);
+        writer.bool(self.interrupt_delay);
+        writer.bool(self.halted);
+        match self.pending_interrupt {
+            Some(instruction) => {
+                writer.bool(true);
+                instruction.iter().for_each(|byte| writer.u8(*byte));
+            }
+            None => writer.bool(false),
+        }
+        writer.block(&self.memory);
+    }
+
+    // Reads everything before changing anything, so a bad state leaves the
+    // registers and memory as they were
+    pub fn load(&mut self, reader: &mut Reader) -> Result<(), String> {
+        let mut registers = [0; 7];
+        for register in registers.iter_mut() {
+            *register = reader.u8()?;
+        }
+        let sp = reader.u16()?;
+        let pc = reader.u16()?;
+        let psw = reader.u8()?;
+        let interrupts = reader.bool()?;
+        let interrupt_delay = reader.bool()?;
+        let halted = reader.bool()?;
+        let pending_interrupt = if reader.bool()? {
+            Some([reader.u8()?, reader.u8()?, reader.u8()?])
+        } else {
+            None
+        };
+        let memory = reader.block()?;
+        if memory.len() != self.memory.len() {
+            return Err(format!("save state has {} bytes of memory", memory.len()));
+        }
+
+        let [a, b, c, d, e, h, l] = registers;
+        self.a = a;
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.e = e;
+        self.h = h;
+        self.l = l;
+        self.sp = sp;
+        self.pc = pc;
+        self.condition.set_psw(psw);
+        self.interrupts = interrupts;
+        self.interrupt_delay = interrupt_delay;
+        self.halted = halted;
+        self.pending_interrupt = pending_interrupt;
+        self.memory.copy_from_slice(memory);
+        Ok(())
+    }
+}
+
+impl Cpu {
+    // The state and the cycle count, the devices are up to the machine
+    pub fn save(&self, writer: &mut Writer) {
+        self.state.save(writer);
+        writer.u64(self.cycles);
+    }
+
+    pub fn load(&mut self, reader: &mut Reader) -> Result<(), String> {
+        self.state.load(reader)?;
+        self.cycles = reader.u64()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+// Names for addresses, so listings, traces and the debugger can show
+// `draw_alien` rather than `$1a2b`.
+//
+// Symbol files hold one symbol per line, either as an address and a name
+//
+//     1a2b draw_alien
+//
+// or as a simple TOML table of names to addresses
+//
+//     [symbols]
+//     draw_alien = 0x1a2b
+//
+// Addresses in the first form are hex, with or without a $ or 0x. Lines
+// starting with ; or # are comments. Writing a set of symbols out gives the
+// first form.
+use crate::assembler::is_operation;
+use crate::disassembler::{PAIRS, REGISTERS, STACK_PAIRS};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Default::default()
+    }
+
+    // Names an address, replacing any name it already had. Names ignore case
+    // like the assembler's labels do, so one that was on another address
+    // moves here.
+    pub fn insert(&mut self, address: u16, name: &str) {
+        self.names
+            .retain(|_, symbol| !symbol.eq_ignore_ascii_case(name));
+        self.names.insert(address, name.to_string());
+    }
+
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(|name| name.as_str())
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.names
+            .iter()
+            .find(|(_, symbol)| symbol.as_str() == name)
+            .map(|(address, _)| *address)
+    }
+
+    // The closest symbol at or below `address` and how far past it `address`
+    // is, for showing addresses inside a routine as `name+offset`
+    pub fn nearest(&self, address: u16) -> Option<(&str, u16)> {
+        self.names
+            .range(..=address)
+            .next_back()
+            .map(|(start, name)| (name.as_str(), address - start))
+    }
+
+    // `address` as `name` or `name+offset`, or in hex if nothing is below it
+    pub fn describe(&self, address: u16) -> String {
+        match self.nearest(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("${:04x}", address),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names.iter().map(|(address, name)| (*address, name.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // Adds every symbol from `other`, its names win
+    pub fn extend(&mut self, other: &Symbols) {
+        for (address, name) in other.iter() {
+            self.insert(address, name);
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            // TOML table headers
+            if line.starts_with('[') {
+                continue;
+            }
+            let error = |message: &str| format!("line {}: {}", index + 1, message);
+
+            let (address, name) = match line.find('=') {
+                // name = 0x1a2b
+                Some(equals) => {
+                    let name = line[..equals].trim().trim_matches('"');
+                    let value = line[equals + 1..].split('#').next().unwrap().trim();
+                    let address = match value.strip_prefix("0x") {
+                        Some(hex) => u16::from_str_radix(hex, 16),
+                        None => value.parse::<u16>(),
+                    };
+                    (address, name)
+                }
+                // 1a2b name
+                None => {
+                    let mut fields = line.split_whitespace();
+                    let value = fields.next().unwrap();
+                    let hex = value
+                        .strip_prefix('$')
+                        .or_else(|| value.strip_prefix("0x"))
+                        .unwrap_or(value);
+                    (u16::from_str_radix(hex, 16), fields.next().unwrap_or(""))
+                }
+            };
+            let address = address.map_err(|_| error("bad address"))?;
+            if !is_name(name) {
+                return Err(error(&format!("{} isn't a usable name", name)));
+            }
+            if symbols
+                .iter()
+                .any(|(_, symbol)| symbol.eq_ignore_ascii_case(name))
+            {
+                return Err(error(&format!("{} is already used", name)));
+            }
+            symbols.insert(address, name);
+        }
+        Ok(symbols)
+    }
+
+    pub fn read(path: &Path) -> Result<Symbols, String> {
+        let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        Symbols::parse(&text).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+}
+
+// Names have to work as assembler labels, so listings using them reassemble.
+// Register names can't be, `MOV A,B` would read as a move from the register,
+// and neither can mnemonics or directives, `end EQU $20c0` would end the
+// source there.
+fn is_name(name: &str) -> bool {
+    let reserved = is_operation(name)
+        || REGISTERS
+            .iter()
+            .chain(&PAIRS)
+            .chain(&STACK_PAIRS)
+            .any(|register| register.eq_ignore_ascii_case(name));
+    if reserved {
+        return false;
+    }
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+impl fmt::Display for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (address, name) in self.iter() {
+            writeln!(f, "{:04x} {}", address, name)?;
+        }
+        Ok(())
+    }
+}
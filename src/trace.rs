@@ -0,0 +1,378 @@
+// Instruction traces. The cpu hands each instruction to the tracer before
+// running it, with the registers as they were, and the tracer writes out the
+// ones that get through its filters. Tracing everything at 2MHz is a lot of
+// output, so it can be cut down to a range of addresses or frames, written in
+// a compact binary form, or kept in a ring that's only written out when the
+// cpu stops at a breakpoint or halts. Interrupts being taken aren't traced,
+// the handler's first instruction just follows whatever was interrupted.
+//
+// Text traces have a line per instruction
+//
+//     18d4  31 00 24  LXI SP,$2400     AF=0002 BC=0000 DE=0000 HL=0000 SP=0000 ----- CYC=10
+//
+// With symbols, text traces name the addresses in instructions and end each
+// line with where PC is
+//
+//     1a5f  cd 3b 1a  CALL draw_alien   AF=0002 BC=0000 DE=0000 HL=0000 SP=2400 ----- CYC=10  ; next_alien+4
+//
+// and binary traces are a header followed by RECORD_SIZE byte records.
+use crate::cpu::instruction_length;
+use crate::disassembler::{decode, Instruction};
+use crate::intel8080_state::StateIntel8080;
+use crate::symbols::Symbols;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+
+// Start of a binary trace, the last byte is the format version
+pub const MAGIC: &[u8; 8] = b"I8080TR\x01";
+pub const RECORD_SIZE: usize = 23;
+
+// The cpu just before an instruction runs
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Record {
+    // Cycles executed before this instruction
+    pub cycles: u64,
+    pub pc: u16,
+    // The instruction, padded out with zeros
+    pub bytes: [u8; 3],
+    pub a: u8,
+    // Flags laid out as PUSH PSW stores them
+    pub flags: u8,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+}
+
+impl Record {
+    // `bytes` are the opcode and the two after it, those the instruction
+    // doesn't use are dropped so both formats read back the same
+    pub fn new(cycles: u64, state: &StateIntel8080, bytes: [u8; 3]) -> Record {
+        let mut bytes = bytes;
+        let length = instruction_length(bytes[0]) as usize;
+        for byte in &mut bytes[length..] {
+            *byte = 0;
+        }
+        let pair = |high: u8, low: u8| (high as u16) << 8 | low as u16;
+        Record {
+            cycles,
+            pc: state.pc,
+            bytes,
+            a: state.a,
+            flags: state.condition.to_psw(),
+            bc: pair(state.b, state.c),
+            de: pair(state.d, state.e),
+            hl: pair(state.h, state.l),
+            sp: state.sp,
+        }
+    }
+
+    pub fn af(&self) -> u16 {
+        (self.a as u16) << 8 | self.flags as u16
+    }
+
+    pub fn instruction(&self) -> Instruction {
+        Instruction {
+            address: self.pc,
+            ..decode(&self.bytes, 0)
+        }
+    }
+
+    // The flags as letters, - for those that are clear
+    pub fn flag_letters(&self) -> String {
+        [
+            (0x80, 'S'),
+            (0x40, 'Z'),
+            (0x10, 'A'),
+            (0x04, 'P'),
+            (0x01, 'C'),
+        ]
+        .iter()
+        .map(|&(bit, name)| if self.flags & bit != 0 { name } else { '-' })
+        .collect()
+    }
+
+    // The text trace line, naming addresses from `symbols`. The names are
+    // skipped when it's read back.
+    pub fn with_symbols(&self, symbols: &Symbols) -> String {
+        let instruction = self.instruction();
+        let bytes: Vec<String> = instruction
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let mut line = format!(
+            "{:04x}  {:<8}  {:<16} AF={:04x} BC={:04x} DE={:04x} HL={:04x} SP={:04x} {} CYC={}",
+            self.pc,
+            bytes.join(" "),
+            instruction.with_symbols(symbols),
+            self.af(),
+            self.bc,
+            self.de,
+            self.hl,
+            self.sp,
+            self.flag_letters(),
+            self.cycles
+        );
+        if symbols.nearest(self.pc).is_some() {
+            line.push_str(&format!("  ; {}", symbols.describe(self.pc)));
+        }
+        line
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.cycles.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[10..13].copy_from_slice(&self.bytes);
+        bytes[13] = self.a;
+        bytes[14] = self.flags;
+        bytes[15..17].copy_from_slice(&self.bc.to_le_bytes());
+        bytes[17..19].copy_from_slice(&self.de.to_le_bytes());
+        bytes[19..21].copy_from_slice(&self.hl.to_le_bytes());
+        bytes[21..23].copy_from_slice(&self.sp.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Record {
+        let word = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(&bytes[0..8]);
+        Record {
+            cycles: u64::from_le_bytes(cycles),
+            pc: word(8),
+            bytes: [bytes[10], bytes[11], bytes[12]],
+            a: bytes[13],
+            flags: bytes[14],
+            bc: word(15),
+            de: word(17),
+            hl: word(19),
+            sp: word(21),
+        }
+    }
+
+    // Reads a line of a text trace back in
+    pub fn parse(line: &str) -> Option<Record> {
+        // Nothing after the ; is read back, symbol names could look like bytes
+        let line = line.split(';').next().unwrap();
+        let mut words = line.split_whitespace().peekable();
+        let mut record = Record {
+            pc: u16::from_str_radix(words.next()?, 16).ok()?,
+            ..Default::default()
+        };
+        // The bytes come first, no mnemonic is two hex digits. Operands can
+        // be, so they stop at the first word that isn't a byte.
+        for count in 0..3 {
+            let byte = match words.peek() {
+                Some(word) if word.len() == 2 => u8::from_str_radix(word, 16).ok(),
+                _ => None,
+            };
+            match byte {
+                Some(byte) => record.bytes[count] = byte,
+                None => break,
+            }
+            words.next();
+        }
+        for word in words {
+            let mut fields = word.splitn(2, '=');
+            let (key, value) = match (fields.next(), fields.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => continue,
+            };
+            match key {
+                "AF" => {
+                    let af = u16::from_str_radix(value, 16).ok()?;
+                    record.a = (af >> 8) as u8;
+                    record.flags = af as u8;
+                }
+                "BC" => record.bc = u16::from_str_radix(value, 16).ok()?,
+                "DE" => record.de = u16::from_str_radix(value, 16).ok()?,
+                "HL" => record.hl = u16::from_str_radix(value, 16).ok()?,
+                "SP" => record.sp = u16::from_str_radix(value, 16).ok()?,
+                "CYC" => record.cycles = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        Some(record)
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.with_symbols(&Symbols::new()))
+    }
+}
+
+// Reads a whole trace, either format
+pub fn read(data: &[u8]) -> Result<Vec<Record>, String> {
+    if let Some(records) = data.strip_prefix(&MAGIC[..]) {
+        let chunks = records.chunks_exact(RECORD_SIZE);
+        if !chunks.remainder().is_empty() {
+            return Err("trace ends part way through a record".to_string());
+        }
+        return Ok(chunks
+            .map(|chunk| {
+                let mut bytes = [0; RECORD_SIZE];
+                bytes.copy_from_slice(chunk);
+                Record::from_bytes(&bytes)
+            })
+            .collect());
+    }
+
+    let text = String::from_utf8_lossy(data);
+    let mut records = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        match Record::parse(line) {
+            Some(record) => records.push(record),
+            None => return Err(format!("line {}: not a trace line", index + 1)),
+        }
+    }
+    Ok(records)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+pub struct Tracer {
+    output: Box<dyn Write>,
+    format: Format,
+    // Only instructions with PC in this range are traced
+    pub addresses: Option<RangeInclusive<u16>>,
+    // Only instructions in these frames are traced, counting frames of
+    // frame_length cycles from the start
+    pub frames: Option<Range<u64>>,
+    pub frame_length: u64,
+    // Keeps only the last ring_size instructions, written out by stop
+    pub ring_size: Option<usize>,
+    ring: VecDeque<Record>,
+    // Names for addresses in text traces
+    pub symbols: Symbols,
+    // The first write that failed, nothing more is written after it
+    pub error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>, format: Format) -> Tracer {
+        let mut tracer = Tracer {
+            output,
+            format,
+            addresses: None,
+            frames: None,
+            frame_length: 1,
+            ring_size: None,
+            ring: VecDeque::new(),
+            symbols: Symbols::new(),
+            error: None,
+        };
+        if format == Format::Binary {
+            let result = tracer.output.write_all(MAGIC);
+            tracer.check(result);
+        }
+        tracer
+    }
+
+    pub fn create(path: &Path, format: Format) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file)), format))
+    }
+
+    // Called by the cpu before each instruction
+    pub fn record(&mut self, record: Record) {
+        if !self.wants(&record) {
+            return;
+        }
+        match self.ring_size {
+            Some(size) => {
+                if self.ring.len() == size {
+                    self.ring.pop_front();
+                }
+                if size > 0 {
+                    self.ring.push_back(record);
+                }
+            }
+            None => self.write(&record),
+        }
+    }
+
+    // Called by the cpu when it stops at a breakpoint or halts, writes out
+    // what the ring is holding
+    pub fn stop(&mut self, reason: &str, pc: u16) {
+        if self.ring.is_empty() {
+            return;
+        }
+        if self.format == Format::Text {
+            let result = writeln!(
+                self.output,
+                "; last {} instructions before {} at {:04x}",
+                self.ring.len(),
+                reason,
+                pc
+            );
+            self.check(result);
+        }
+        while let Some(record) = self.ring.pop_front() {
+            self.write(&record);
+        }
+        let result = self.output.flush();
+        self.check(result);
+    }
+
+    pub fn flush(&mut self) {
+        let result = self.output.flush();
+        self.check(result);
+    }
+
+    fn wants(&self, record: &Record) -> bool {
+        let address = match &self.addresses {
+            Some(range) => range.contains(&record.pc),
+            None => true,
+        };
+        let frame = match &self.frames {
+            Some(range) => range.contains(&(record.cycles / self.frame_length.max(1))),
+            None => true,
+        };
+        address && frame
+    }
+
+    fn write(&mut self, record: &Record) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = match self.format {
+            Format::Text => writeln!(self.output, "{}", record.with_symbols(&self.symbols)),
+            Format::Binary => self.output.write_all(&record.to_bytes()),
+        };
+        self.check(result);
+    }
+
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(error) = result {
+            if self.error.is_none() {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("addresses", &self.addresses)
+            .field("frames", &self.frames)
+            .field("ring_size", &self.ring_size)
+            .field("symbols", &self.symbols.len())
+            .finish()
+    }
+}
@@ -0,0 +1,239 @@
+// Compares one of our traces against a log from another emulator, to find
+// the first instruction where the two disagree. Most emulators can log a
+// line per instruction with the registers on it, like
+//
+//     PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000
+//     A:00 F:02 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0100
+//
+// so the reference is read as KEY:VALUE or KEY=VALUE pairs in hex, in any
+// order, with only PC required. Registers a log leaves out aren't compared.
+use crate::trace::Record;
+use std::fmt::Write;
+
+// Records shown before the difference
+pub const CONTEXT: usize = 4;
+
+// The flags an 8080 actually has, other emulators differ on the unused bits
+const FLAGS: [(u8, &str); 5] = [
+    (0x80, "S"),
+    (0x40, "Z"),
+    (0x10, "AC"),
+    (0x04, "P"),
+    (0x01, "CY"),
+];
+
+// A line from the reference log
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Reference {
+    pub pc: u16,
+    pub a: Option<u8>,
+    pub flags: Option<u8>,
+    pub bc: Option<u16>,
+    pub de: Option<u16>,
+    pub hl: Option<u16>,
+    pub sp: Option<u16>,
+    pub line: String,
+}
+
+impl Reference {
+    pub fn parse(line: &str) -> Option<Reference> {
+        let mut reference = Reference {
+            line: line.to_string(),
+            ..Default::default()
+        };
+        let mut pc = None;
+        let (mut b, mut c, mut d, mut e, mut h, mut l) = (None, None, None, None, None, None);
+
+        // `KEY: VALUE` is joined back up into one word
+        let mut words = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|word| !word.is_empty());
+        while let Some(word) = words.next() {
+            let word = if word.ends_with(':') || word.ends_with('=') {
+                format!("{}{}", word, words.next().unwrap_or(""))
+            } else {
+                word.to_string()
+            };
+            let separator = match word.find(&[':', '='][..]) {
+                Some(separator) => separator,
+                None => continue,
+            };
+            let key = word[..separator].to_ascii_uppercase();
+            let value = match hex(&word[separator + 1..]) {
+                Some(value) => value,
+                None => continue,
+            };
+            match key.as_str() {
+                "PC" => pc = Some(value),
+                "SP" => reference.sp = Some(value),
+                "AF" => {
+                    reference.a = Some((value >> 8) as u8);
+                    reference.flags = Some(value as u8);
+                }
+                "BC" => reference.bc = Some(value),
+                "DE" => reference.de = Some(value),
+                "HL" => reference.hl = Some(value),
+                "A" => reference.a = Some(value as u8),
+                "F" => reference.flags = Some(value as u8),
+                "B" => b = Some(value as u8),
+                "C" => c = Some(value as u8),
+                "D" => d = Some(value as u8),
+                "E" => e = Some(value as u8),
+                "H" => h = Some(value as u8),
+                "L" => l = Some(value as u8),
+                _ => {}
+            }
+        }
+
+        let pair = |high: Option<u8>, low: Option<u8>| Some((high? as u16) << 8 | low? as u16);
+        reference.bc = reference.bc.or_else(|| pair(b, c));
+        reference.de = reference.de.or_else(|| pair(d, e));
+        reference.hl = reference.hl.or_else(|| pair(h, l));
+        reference.pc = pc?;
+        Some(reference)
+    }
+}
+
+// Where the two logs part ways
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    // Index of the first record that differs
+    pub index: usize,
+    // What differs, like "PC", "HL" or "flag AC", with both values
+    pub register: String,
+    pub ours: u64,
+    pub theirs: u64,
+}
+
+// Reads a reference log, skipping lines without a PC on them
+pub fn read_reference(text: &str) -> Vec<Reference> {
+    text.lines().filter_map(Reference::parse).collect()
+}
+
+// The first record where the logs disagree. When one log is longer than the
+// other the register is "end" and the values are the lengths.
+pub fn compare(ours: &[Record], theirs: &[Reference]) -> Option<Divergence> {
+    for (index, (record, reference)) in ours.iter().zip(theirs).enumerate() {
+        if let Some((register, ours, theirs)) = difference(record, reference) {
+            return Some(Divergence {
+                index,
+                register,
+                ours,
+                theirs,
+            });
+        }
+    }
+    if ours.len() != theirs.len() {
+        return Some(Divergence {
+            index: ours.len().min(theirs.len()),
+            register: "end".to_string(),
+            ours: ours.len() as u64,
+            theirs: theirs.len() as u64,
+        });
+    }
+    None
+}
+
+fn difference(record: &Record, reference: &Reference) -> Option<(String, u64, u64)> {
+    let registers = [
+        ("PC", Some(record.pc), Some(reference.pc)),
+        ("A", Some(record.a as u16), reference.a.map(u16::from)),
+        ("BC", Some(record.bc), reference.bc),
+        ("DE", Some(record.de), reference.de),
+        ("HL", Some(record.hl), reference.hl),
+        ("SP", Some(record.sp), reference.sp),
+    ];
+    for (name, ours, theirs) in registers.iter() {
+        if let (Some(ours), Some(theirs)) = (ours, theirs) {
+            if ours != theirs {
+                return Some((name.to_string(), *ours as u64, *theirs as u64));
+            }
+        }
+    }
+    let flags = reference.flags?;
+    FLAGS
+        .iter()
+        .find(|(bit, _)| record.flags & bit != flags & bit)
+        .map(|(bit, name)| {
+            (
+                format!("flag {}", name),
+                (record.flags & bit != 0) as u64,
+                (flags & bit != 0) as u64,
+            )
+        })
+}
+
+// Describes a divergence with the records leading up to it from both logs
+pub fn report(ours: &[Record], theirs: &[Reference], divergence: &Divergence) -> String {
+    let index = divergence.index;
+    let mut output = String::new();
+
+    if divergence.register == "end" {
+        writeln!(
+            output,
+            "logs agree for {} instructions, ours has {} and the reference {}",
+            index, divergence.ours, divergence.theirs
+        )
+        .unwrap();
+    } else {
+        writeln!(output, "first difference at instruction {}", index).unwrap();
+        // Each record is the state before an instruction, so the one that
+        // went wrong is the instruction before it
+        let cause = match index.checked_sub(1).map(|previous| &ours[previous]) {
+            Some(previous) => format!(
+                "after {:04x} {}",
+                previous.pc,
+                previous.instruction().to_string().trim_start()
+            ),
+            None => "at the start".to_string(),
+        };
+        let width = match divergence.register.as_str() {
+            "A" => 2,
+            register if register.starts_with("flag") => 1,
+            _ => 4,
+        };
+        writeln!(
+            output,
+            "{} {}: ours {:0width$x}, reference {:0width$x}",
+            cause,
+            divergence.register,
+            divergence.ours,
+            divergence.theirs,
+            width = width
+        )
+        .unwrap();
+    }
+
+    let start = index.saturating_sub(CONTEXT);
+    writeln!(output, "\nours:").unwrap();
+    for (offset, record) in ours.iter().enumerate().skip(start).take(index - start + 1) {
+        writeln!(output, "{}{}", marker(offset, index), record).unwrap();
+    }
+    writeln!(output, "\nreference:").unwrap();
+    for (offset, reference) in theirs
+        .iter()
+        .enumerate()
+        .skip(start)
+        .take(index - start + 1)
+    {
+        writeln!(output, "{}{}", marker(offset, index), reference.line.trim()).unwrap();
+    }
+    output
+}
+
+fn marker(offset: usize, index: usize) -> &'static str {
+    if offset == index {
+        "> "
+    } else {
+        "  "
+    }
+}
+
+fn hex(value: &str) -> Option<u16> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix('$'))
+        .unwrap_or(value)
+        .trim_end_matches(&['h', 'H'][..]);
+    u16::from_str_radix(digits, 16).ok()
+}
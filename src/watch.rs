@@ -0,0 +1,115 @@
+// Watchpoints on memory and I/O ports. Every data access the cpu makes is
+// checked against them, and the first one to match is held on to so
+// run_until can stop after the instruction that made it. Instruction fetches
+// aren't data accesses, so code under a read watchpoint still runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    // Memory reads, or IN for a port
+    Read,
+    // Memory writes, or OUT for a port
+    Write,
+    Both,
+}
+
+impl Access {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::Both => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watch {
+    // Accesses to `start..=end`
+    Memory {
+        start: u16,
+        end: u16,
+        access: Access,
+    },
+    // IN or OUT on `port`, only when the byte moved is `value` if there is one
+    Port {
+        port: u8,
+        access: Access,
+        value: Option<u8>,
+    },
+}
+
+// An access that matched a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hit {
+    Read { address: u16, value: u8 },
+    Write { address: u16, value: u8 },
+    Input { port: u8, value: u8 },
+    Output { port: u8, value: u8 },
+}
+
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    pub watches: Vec<Watch>,
+    // The first access to match during the last instruction
+    pub hit: Option<Hit>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Default::default()
+    }
+
+    pub fn read(&mut self, address: u16, value: u8) {
+        if self.memory(address, false) {
+            self.record(Hit::Read { address, value });
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if self.memory(address, true) {
+            self.record(Hit::Write { address, value });
+        }
+    }
+
+    pub fn input(&mut self, port: u8, value: u8) {
+        if self.port(port, value, false) {
+            self.record(Hit::Input { port, value });
+        }
+    }
+
+    pub fn output(&mut self, port: u8, value: u8) {
+        if self.port(port, value, true) {
+            self.record(Hit::Output { port, value });
+        }
+    }
+
+    fn memory(&self, address: u16, write: bool) -> bool {
+        self.watches.iter().any(|watch| match *watch {
+            Watch::Memory { start, end, access } => {
+                start <= address && address <= end && access.matches(write)
+            }
+            _ => false,
+        })
+    }
+
+    fn port(&self, port: u8, value: u8, write: bool) -> bool {
+        self.watches.iter().any(|watch| match *watch {
+            Watch::Port {
+                port: watched,
+                access,
+                value: condition,
+            } => {
+                watched == port
+                    && access.matches(write)
+                    && (condition.is_none() || condition == Some(value))
+            }
+            _ => false,
+        })
+    }
+
+    // Later accesses in the same instruction don't replace the first
+    fn record(&mut self, hit: Hit) {
+        if self.hit.is_none() {
+            self.hit = Some(hit);
+        }
+    }
+}
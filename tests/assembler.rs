@@ -0,0 +1,93 @@
+// Assembles source into the bytes the disassembler and cpu agree on
+use invaders_dis::assembler::{assemble, Error};
+use invaders_dis::disassembler::decode;
+use invaders_dis::listing::{Listing, ENTRY_POINTS};
+use invaders_dis::Cpu;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn every_documented_opcode() {
+    for opcode in 0..=255u8 {
+        let instruction = decode(&[opcode, 0x34, 0x12], 0);
+        if instruction.undocumented {
+            continue;
+        }
+        let program = assemble(&format!(" {}", instruction)).unwrap();
+        assert_eq!(program.bytes, instruction.bytes, "{}", instruction);
+    }
+}
+
+#[test]
+fn invaders_listing_reassembles() {
+    let rom = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("invaders")).unwrap();
+    let listing = Listing::new(&rom, 0, &ENTRY_POINTS).to_string();
+    let program = assemble(&listing).unwrap();
+    assert_eq!(program.origin, 0);
+    assert!(program.bytes == rom);
+}
+
+#[test]
+fn directives_and_expressions() {
+    let source = "
+COUNT   EQU     END - TABLE     ; forward references work in EQU
+        ORG     $100
+START:  LXI     H,TABLE
+        MVI     B,COUNT
+        MVI     C,(1 << 4) | 0fh & ~1
+        JMP     $ + 3 * 2
+        DS      2
+TABLE:  DB      'Hi', 'A' + 1, -1, 0x7f
+        DW      START, 1010b
+END:
+";
+    let program = assemble(source).unwrap();
+    assert_eq!(program.origin, 0x100);
+    assert_eq!(
+        program.bytes,
+        [
+            0x21, 0x0c, 0x01, // LXI H,TABLE
+            0x06, 0x09, // MVI B,COUNT
+            0x0e, 0x1e, // MVI C,$1e
+            0xc3, 0x0d, 0x01, // JMP $+6
+            0x00, 0x00, // DS 2
+            b'H', b'i', b'B', 0xff, 0x7f, 0x00, 0x01, 0x0a, 0x00,
+        ][..]
+    );
+    assert_eq!(program.symbols["COUNT"], 9);
+    assert_eq!(program.symbols["START"], 0x100);
+}
+
+#[test]
+fn runs_on_the_cpu() {
+    let source = "
+        ORG     0
+        LXI     SP,$2400
+        MVI     A,5
+LOOP:   DCR     A
+        JNZ     LOOP
+        HLT
+";
+    let mut cpu = Cpu::new(assemble(source).unwrap().bytes);
+    while !cpu.halted() {
+        cpu.step();
+    }
+    assert_eq!(cpu.state.a, 0);
+    assert_eq!(cpu.state.pc, 0x000a);
+}
+
+#[test]
+fn errors_name_the_line() {
+    let error = |source: &str| assemble(source).err().unwrap();
+    assert_eq!(
+        error(" NOP\n MOV A,Q"),
+        Error {
+            line: 2,
+            message: "Q isn't a register".to_string()
+        }
+    );
+    assert_eq!(error(" JMP NOWHERE").message, "NOWHERE isn't defined");
+    assert_eq!(error(" MVI A,256").message, "256 doesn't fit in a byte");
+    assert_eq!(error("A: NOP\na: NOP").message, "a is already defined");
+    assert_eq!(error(" FOO").message, "unknown instruction FOO");
+}
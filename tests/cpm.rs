@@ -0,0 +1,109 @@
+// CP/M machine mode, BDOS console and file calls against a host directory
+use invaders_dis::cpm::{BufferConsole, Cpm, TPA};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const FCB: u16 = 0x005c;
+
+// MVI C,function ; LXI D,de ; CALL 5
+fn bdos_call(function: u8, de: u16) -> Vec<u8> {
+    vec![0x0e, function, 0x11, de as u8, (de >> 8) as u8, 0xcd, 0x05, 0x00]
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("invaders_dis_{}_{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(dir: &Path, program: &[u8], args: &[&str]) -> String {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let mut cpm = Cpm::new(BufferConsole::default(), dir.to_path_buf());
+    cpm.load(program, &args).unwrap();
+    cpm.run();
+    assert!(cpm.exited, "stopped at {:04x}", cpm.cpu.state.pc);
+    String::from_utf8_lossy(&cpm.console.output).into_owned()
+}
+
+#[test]
+fn prints_string_and_exits() {
+    let dir = scratch_dir("print");
+    // LXI D,msg ; MVI C,9 ; CALL 5 ; JMP 0 ; msg
+    let mut program = bdos_call(9, TPA + 11);
+    program.extend_from_slice(&[0xc3, 0x00, 0x00]);
+    program.extend_from_slice(b"HELLO CP/M$");
+    assert_eq!(run(&dir, &program, &[]), "HELLO CP/M");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn ret_returns_to_cp_m() {
+    let dir = scratch_dir("ret");
+    // LXI D,msg ; MVI C,9 ; CALL 5 ; RET ; msg
+    let mut program = bdos_call(9, TPA + 9);
+    program.push(0xc9);
+    program.extend_from_slice(b"HI$");
+    assert_eq!(run(&dir, &program, &[]), "HI");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn writes_and_reads_back_a_file() {
+    let dir = scratch_dir("files");
+
+    // Make OUT.TXT from the default FCB, write one record from msg, close
+    let msg = TPA + 4 * 8 + 3;
+    let mut writer = bdos_call(22, FCB);
+    writer.extend(bdos_call(26, msg));
+    writer.extend(bdos_call(21, FCB));
+    writer.extend(bdos_call(16, FCB));
+    writer.extend_from_slice(&[0xc3, 0x00, 0x00]);
+    let mut record = b"SAVED$".to_vec();
+    record.resize(128, 0x1a);
+    writer.extend(&record);
+    run(&dir, &writer, &["out.txt"]);
+
+    let saved = fs::read(dir.join("OUT.TXT")).unwrap();
+    assert_eq!(saved, record);
+
+    // Open it, read the record into the default DMA buffer and print it
+    let mut reader = bdos_call(15, FCB);
+    reader.extend(bdos_call(20, FCB));
+    reader.extend(bdos_call(9, 0x0080));
+    reader.extend_from_slice(&[0xc3, 0x00, 0x00]);
+    assert_eq!(run(&dir, &reader, &["OUT.TXT"]), "SAVED");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn programs_have_to_fit_below_the_bdos() {
+    let dir = scratch_dir("size");
+    let mut cpm = Cpm::new(BufferConsole::default(), dir.clone());
+    assert_eq!(
+        cpm.load(&vec![0; 0x10000], &[]).unwrap_err(),
+        "program is 65536 bytes, only 64768 fit below the BDOS"
+    );
+    assert!(cpm.load(&vec![0; 0xfd00], &[]).is_ok());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn opens_read_only_files() {
+    let dir = scratch_dir("read_only");
+    let path = dir.join("RO.TXT");
+    fs::write(&path, b"READ ONLY$").unwrap();
+    let mut permissions = fs::metadata(&path).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&path, permissions).unwrap();
+
+    let mut reader = bdos_call(15, FCB);
+    reader.extend(bdos_call(20, FCB));
+    reader.extend(bdos_call(9, 0x0080));
+    reader.extend_from_slice(&[0xc3, 0x00, 0x00]);
+    // Stops the print if the file didn't open and the DMA buffer has no $
+    reader.push(b'$');
+    assert_eq!(run(&dir, &reader, &["RO.TXT"]), "READ ONLY");
+
+    fs::remove_dir_all(dir).unwrap();
+}
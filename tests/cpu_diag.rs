@@ -0,0 +1,80 @@
+// Runs the well known 8080 diagnostic programs against the core
+// The programs are CP/M .COM files, they run on the CP/M machine and print
+// their results through the BDOS console calls, which are captured here.
+// See tests/roms/README.md for where to get them.
+use invaders_dis::cpm::{BufferConsole, Cpm};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Gives up if a program runs away instead of returning to CP/M
+const MAX_CYCLES: u64 = 50_000_000_000;
+
+fn roms() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("roms")
+}
+
+// Loads a .COM from tests/roms, failing loudly if it isn't there
+fn load_com(name: &str) -> Vec<u8> {
+    let path = roms().join(name);
+    fs::read(&path)
+        .unwrap_or_else(|error| panic!("{}: {}, see tests/roms/README.md", path.display(), error))
+}
+
+// Runs a program until it returns to CP/M and returns everything it printed
+fn run_com(program: &[u8]) -> String {
+    let mut cpm = Cpm::new(BufferConsole::default(), roms());
+    cpm.load(program, &[]).unwrap();
+
+    while !cpm.exited {
+        // step also gives None when the program returns to CP/M
+        if cpm.step().is_none() && !cpm.exited {
+            panic!(
+                "cpu stopped at {:04x}, output so far:\n{}",
+                cpm.cpu.state.pc,
+                String::from_utf8_lossy(&cpm.console.output)
+            );
+        }
+        if cpm.cpu.cycles > MAX_CYCLES {
+            panic!(
+                "program never finished, output so far:\n{}",
+                String::from_utf8_lossy(&cpm.console.output)
+            );
+        }
+    }
+    String::from_utf8_lossy(&cpm.console.output).into_owned()
+}
+
+#[test]
+fn tst8080() {
+    let program = load_com("TST8080.COM");
+    let output = run_com(&program);
+    assert!(output.contains("CPU IS OPERATIONAL"), "{}", output);
+}
+
+#[test]
+fn preliminary_8080pre() {
+    let program = load_com("8080PRE.COM");
+    let output = run_com(&program);
+    assert!(!output.contains("ERROR"), "{}", output);
+    assert!(output.contains("Preliminary tests complete"), "{}", output);
+}
+
+#[test]
+fn cputest() {
+    let program = load_com("CPUTEST.COM");
+    let output = run_com(&program);
+    assert!(output.contains("CPU TESTS OK"), "{}", output);
+}
+
+// Exercises every instruction against CRCs from real hardware, takes
+// minutes in a debug build
+#[test]
+#[ignore = "slow"]
+fn exerciser_8080exm() {
+    let program = load_com("8080EXM.COM");
+    let output = run_com(&program);
+    assert!(!output.contains("ERROR"), "{}", output);
+    assert!(output.contains("Tests complete"), "{}", output);
+}
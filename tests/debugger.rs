@@ -0,0 +1,135 @@
+// Drives the debugger the way a terminal would, one command line at a time
+use invaders_dis::assembler::assemble;
+use invaders_dis::debugger::Debugger;
+use invaders_dis::symbols::Symbols;
+use invaders_dis::Cpu;
+
+const PROGRAM: &str = "
+        ORG     0
+        LXI     SP,$100
+        CALL    sub
+        MVI     A,1
+done:   JMP     done
+sub:    MVI     B,2
+        RET
+";
+
+fn start() -> (Cpu, Debugger) {
+    let program = assemble(PROGRAM).unwrap();
+    let mut symbols = Symbols::new();
+    for (name, address) in &program.symbols {
+        symbols.insert(*address, name);
+    }
+    (Cpu::new(program.bytes), Debugger::new(symbols))
+}
+
+#[test]
+fn step_over_a_call() {
+    let (mut cpu, mut debugger) = start();
+    assert_eq!(
+        debugger.command(&mut cpu, "s"),
+        " 0003               CALL sub\n"
+    );
+
+    debugger.command(&mut cpu, "n");
+    assert!(debugger.running);
+    let stopped = debugger.run(&mut cpu, 1000).unwrap();
+    assert_eq!(stopped, "stopped at  0006               MVI A,$01\n");
+    assert_eq!(cpu.state.b, 2);
+    // The breakpoint it ran to is gone again
+    assert!(cpu.breakpoints.is_empty());
+
+    // An empty line repeats the step
+    debugger.command(&mut cpu, "");
+    assert_eq!(cpu.state.pc, 0x0008);
+}
+
+#[test]
+fn breakpoints_stop_a_run() {
+    let (mut cpu, mut debugger) = start();
+    debugger.command(&mut cpu, "b sub");
+    assert_eq!(
+        debugger.command(&mut cpu, "b"),
+        "*000b  sub:         MVI B,$02\n"
+    );
+
+    debugger.command(&mut cpu, "c");
+    assert!(debugger.run(&mut cpu, 1000).is_some());
+    assert_eq!(cpu.state.pc, 0x000b);
+    assert!(debugger.command(&mut cpu, "r").contains("SP=00fe PC=000b"));
+
+    // Carrying on leaves the breakpoint behind and runs to the end
+    debugger.command(&mut cpu, "d sub");
+    debugger.command(&mut cpu, "c");
+    assert!(debugger.run(&mut cpu, 1000).is_none());
+    assert_eq!(cpu.state.pc, 0x0008);
+}
+
+#[test]
+fn edit_and_dump_memory() {
+    let (mut cpu, mut debugger) = start();
+    debugger.command(&mut cpu, "e $20 41 42");
+    assert_eq!(
+        debugger.command(&mut cpu, "x 20 2"),
+        format!("0020  {:<48} AB\n", "41 42")
+    );
+    assert_eq!(debugger.command(&mut cpu, "x"), "x needs an address\n");
+    assert_eq!(
+        debugger.command(&mut cpu, "z"),
+        "unknown command `z`, h for help\n"
+    );
+}
+
+#[test]
+fn lists_around_pc() {
+    let (mut cpu, mut debugger) = start();
+    debugger.command(&mut cpu, "s 2");
+    let listing = debugger.command(&mut cpu, "l");
+    // Backs up to show what came before PC in memory
+    let lines: Vec<&str> = listing.lines().take(6).collect();
+    assert_eq!(
+        lines,
+        [
+            "  0000               LXI SP,$0100",
+            "  0003               CALL sub",
+            "  0006               MVI A,$01",
+            "  0008  done:        JMP done",
+            "> 000b  sub:         MVI B,$02",
+            "  000d               RET",
+        ]
+    );
+}
+
+#[test]
+fn watchpoints_stop_after_the_access() {
+    let program = assemble(
+        "
+        MVI     A,5
+        STA     $20
+        LDA     $20
+        OUT     3
+        MVI     A,7
+        OUT     3
+done:   JMP     done
+",
+    )
+    .unwrap();
+    let mut cpu = Cpu::new(program.bytes);
+    let mut debugger = Debugger::new(Symbols::new());
+
+    // Only the write trips it, not the read after
+    debugger.command(&mut cpu, "w 20 1 w");
+    assert_eq!(debugger.command(&mut cpu, "w"), "0  write $0020\n");
+    debugger.command(&mut cpu, "c");
+    let stopped = debugger.run(&mut cpu, 1000).unwrap();
+    assert!(stopped.starts_with("wrote $05 to $0020\n"), "{}", stopped);
+    assert_eq!(cpu.state.pc, 0x0005);
+
+    // The port only counts when the value matches
+    debugger.command(&mut cpu, "dw 0");
+    debugger.command(&mut cpu, "p 3 out 7");
+    debugger.command(&mut cpu, "c");
+    let stopped = debugger.run(&mut cpu, 1000).unwrap();
+    assert!(stopped.starts_with("OUT $03 sent $07\n"), "{}", stopped);
+    assert_eq!(cpu.state.pc, 0x000e);
+}
@@ -0,0 +1,72 @@
+// Checks the disassembler against the cpu, every instruction it finds must be
+// as long as the cpu takes it to be or every listing after it goes wrong
+use invaders_dis::disassembler::{disassemble, Instruction};
+use invaders_dis::Cpu;
+use std::fs;
+use std::path::Path;
+
+// Return address on the stack and the value of HL, for RET and PCHL
+const INDIRECT: u16 = 0x3abc;
+
+// Runs just `instruction` with the flags set to `psw` and returns the new PC
+fn execute(rom: &[u8], instruction: &Instruction, psw: u8) -> u16 {
+    let mut cpu = Cpu::new(rom.to_vec());
+    cpu.state.pc = instruction.address;
+    cpu.state.sp = 0x3000;
+    cpu.state.memory[0x3000] = INDIRECT as u8;
+    cpu.state.memory[0x3001] = (INDIRECT >> 8) as u8;
+    cpu.state.h = (INDIRECT >> 8) as u8;
+    cpu.state.l = INDIRECT as u8;
+    cpu.state.condition.set_psw(psw);
+    cpu.step();
+    cpu.state.pc
+}
+
+#[test]
+fn invaders_rom_round_trip() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("invaders");
+    let rom = fs::read(path).expect("invaders rom");
+
+    let mut boundary: u16 = 0;
+    for instruction in disassemble(&rom, 0..rom.len()) {
+        assert_eq!(instruction.address, boundary);
+
+        // With every flag clear and then every flag set, each conditional
+        // jump, call and return is taken once and falls through once
+        let mut fell_through = false;
+        for psw in [0x00, 0xff].iter() {
+            let pc = execute(&rom, &instruction, *psw);
+            if pc == instruction.next() {
+                fell_through = true;
+            } else {
+                assert!(
+                    Some(pc) == instruction.target || pc == INDIRECT,
+                    "{:04x} {} went to {:04x}",
+                    instruction.address,
+                    instruction,
+                    pc
+                );
+            }
+        }
+        let unconditional = ["JMP", "CALL", "RET", "RST", "PCHL"].contains(&instruction.mnemonic);
+        assert!(
+            fell_through || unconditional,
+            "{:04x} {} never reached {:04x}",
+            instruction.address,
+            instruction,
+            instruction.next()
+        );
+
+        boundary = instruction.next();
+    }
+    assert_eq!(boundary as usize, rom.len());
+}
+
+#[test]
+fn rst_7_is_one_byte() {
+    let code = [0xff, 0x3e, 0x01];
+    let listing: Vec<String> = disassemble(&code, 0..code.len())
+        .map(|instruction| instruction.to_string())
+        .collect();
+    assert_eq!(listing, ["RST 7", "MVI A,$01"]);
+}
@@ -0,0 +1,96 @@
+// Talks to the GDB stub over a local socket the way GDB would
+use invaders_dis::assembler::assemble;
+use invaders_dis::gdb;
+use invaders_dis::Cpu;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+const PROGRAM: &str = "
+        LXI     SP,$100
+        MVI     A,5
+        STA     $20
+        CALL    sub
+done:   JMP     done
+sub:    INR     A
+        RET
+";
+
+// Sends a packet and returns the reply, checking both get acknowledged
+fn exchange(stream: &mut TcpStream, data: &str) -> String {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    write!(stream, "${}#{:02x}", data, checksum).unwrap();
+
+    let mut reply = Vec::new();
+    let mut byte = [0];
+    stream.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], b'+');
+    stream.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], b'$');
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        if byte[0] == b'#' {
+            break;
+        }
+        reply.push(byte[0]);
+    }
+    let mut sent = [0; 2];
+    stream.read_exact(&mut sent).unwrap();
+    let expected = reply.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    assert_eq!(
+        std::str::from_utf8(&sent).unwrap(),
+        format!("{:02x}", expected)
+    );
+    stream.write_all(b"+").unwrap();
+    String::from_utf8(reply).unwrap()
+}
+
+#[test]
+fn scripted_session() {
+    let program = assemble(PROGRAM).unwrap();
+    let call = program.symbols["sub"];
+    let mut cpu = Cpu::new(program.bytes);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        assert!(
+            exchange(&mut stream, "qSupported:xmlRegisters=i386").contains("qXfer:features:read+")
+        );
+        assert!(
+            exchange(&mut stream, "qXfer:features:read:target.xml:0,1000").starts_with("l<?xml")
+        );
+        assert_eq!(exchange(&mut stream, "?"), "S05");
+
+        // A, flags, BC, DE, HL, SP and PC after the first step
+        assert_eq!(exchange(&mut stream, "s"), "S05");
+        assert_eq!(exchange(&mut stream, "g"), "000200000000000000010300");
+
+        // A watchpoint on the store, then a breakpoint in the subroutine
+        assert_eq!(exchange(&mut stream, "Z2,20,1"), "OK");
+        assert_eq!(exchange(&mut stream, "c"), "T05watch:0020;");
+        assert_eq!(exchange(&mut stream, "m20,2"), "0500");
+        assert_eq!(exchange(&mut stream, "z2,20,1"), "OK");
+        assert_eq!(exchange(&mut stream, &format!("Z0,{:x},1", call)), "OK");
+        assert_eq!(exchange(&mut stream, "c"), "S05");
+        assert_eq!(
+            exchange(&mut stream, "p6"),
+            format!("{:02x}{:02x}", call as u8, call >> 8)
+        );
+
+        // Writing registers and memory
+        assert_eq!(exchange(&mut stream, "P0=41"), "OK");
+        assert_eq!(exchange(&mut stream, "M30,2:abcd"), "OK");
+        assert_eq!(exchange(&mut stream, "m30,2"), "abcd");
+        assert_eq!(exchange(&mut stream, "s"), "S05");
+        assert_eq!(exchange(&mut stream, "p0"), "42");
+        assert_eq!(exchange(&mut stream, "vMustReplyEmpty"), "");
+
+        stream.write_all(b"$k#6b").unwrap();
+    });
+
+    gdb::serve(&mut cpu, listener).unwrap();
+    client.join().unwrap();
+    assert_eq!(cpu.state.memory[0x30], 0xab);
+}
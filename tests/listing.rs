@@ -0,0 +1,40 @@
+// Recursive descent disassembly keeps data out of the code
+use invaders_dis::listing::Listing;
+
+#[test]
+fn jumps_over_data() {
+    let rom = [
+        0xc3, 0x05, 0x00, // JMP L0005
+        0x48, 0x49, // "HI"
+        0xcd, 0x0a, 0x00, // CALL L000a
+        0xc9, // RET
+        0xff, // data
+        0xcb, 0x05, 0x00, // undocumented JMP L0005
+    ];
+    let listing = Listing::new(&rom, 0, &[0]);
+
+    assert!(listing.is_code(0x0002));
+    assert!(!listing.is_code(0x0003));
+    assert!(!listing.is_code(0x0004));
+    assert!(listing.is_code(0x0008));
+    assert!(!listing.is_code(0x0009));
+    assert!(listing.is_code(0x000c));
+
+    let source: Vec<String> = listing
+        .to_string()
+        .lines()
+        .map(|line| line.split(';').next().unwrap().trim_end().to_string())
+        .collect();
+    assert_eq!(
+        source,
+        [
+            "        ORG     $0000",
+            "        JMP     L0005",
+            "        DB      $48,$49",
+            "L0005:  CALL    L000a",
+            "        RET",
+            "        DB      $ff",
+            "L000a:  DB      $cb,$05,$00",
+        ]
+    );
+}
@@ -0,0 +1,129 @@
+// The rewind ring, and going back through frames from the debugger
+use invaders_dis::assembler::assemble;
+use invaders_dis::debugger::{Debugger, Target};
+use invaders_dis::rewind::Rewind;
+use invaders_dis::save_state::{Reader, Writer};
+use invaders_dis::symbols::Symbols;
+use invaders_dis::Cpu;
+
+#[test]
+fn states_come_back_in_order() {
+    // Memory with a few bytes changed each frame, like VRAM under a sprite
+    let mut state = vec![0; 0x10000];
+    let mut states = Vec::new();
+    let mut rewind = Rewind::new(5);
+    for frame in 0..8 {
+        state[0x2400 + frame * 33] = frame as u8 + 1;
+        state[0x20ff] = frame as u8;
+        states.push(state.clone());
+        rewind.push(state.clone());
+    }
+    assert_eq!(rewind.len(), 5);
+    assert!(rewind.size() < 0x10000 + 4 * 64);
+
+    // Back to the oldest of the five kept, and no further
+    for expected in states[3..].iter().rev() {
+        assert_eq!(rewind.latest(), Some(&expected[..]));
+        let more = rewind.pop();
+        assert_eq!(more, expected != &states[3]);
+    }
+    assert_eq!(rewind.latest(), Some(&states[3][..]));
+    assert_eq!(rewind.len(), 1);
+
+    // States don't have to be the same length
+    rewind.push(vec![1, 2, 3]);
+    assert!(rewind.pop());
+    assert_eq!(rewind.latest(), Some(&states[3][..]));
+    rewind.clear();
+    assert!(rewind.is_empty());
+    assert!(!rewind.pop());
+}
+
+// Cycles in each frame of Frames
+const FRAME: u64 = 1000;
+
+// A bare cpu split into frames, keeping the state at the start of each the
+// way the game does
+struct Frames {
+    cpu: Cpu,
+    rewind: Rewind,
+    rewind_cycles: Option<u64>,
+}
+
+impl Target for Frames {
+    fn cpu(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    fn run_until(&mut self, cycle: u64) -> bool {
+        while self.cpu.cycles < cycle {
+            let frame = self.cpu.cycles / FRAME;
+            if !matches!(self.rewind_cycles, Some(cycles) if cycles / FRAME == frame) {
+                let mut writer = Writer::new("test");
+                self.cpu.save(&mut writer);
+                self.rewind.push(writer.bytes);
+                self.rewind_cycles = Some(self.cpu.cycles);
+            }
+            if !self.cpu.run_until(((frame + 1) * FRAME).min(cycle)) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn back_frame(&mut self) -> bool {
+        if self.rewind_cycles == Some(self.cpu.cycles) && !self.rewind.pop() {
+            return false;
+        }
+        let mut reader = match self.rewind.latest() {
+            Some(state) => Reader::new(state, "test").unwrap(),
+            None => return false,
+        };
+        self.cpu.load(&mut reader).unwrap();
+        self.rewind_cycles = Some(self.cpu.cycles);
+        true
+    }
+}
+
+#[test]
+fn back_a_frame_at_a_time() {
+    let program = assemble(
+        "
+loop:   INX     H
+        SHLD    $80
+        JMP     loop
+",
+    )
+    .unwrap();
+    let mut frames = Frames {
+        cpu: Cpu::new(program.bytes),
+        rewind: Rewind::new(10),
+        rewind_cycles: None,
+    };
+    let mut debugger = Debugger::new(Symbols::new());
+    frames.run_until(3500);
+    let counted = frames.cpu.state.memory[0x80];
+
+    // To the start of the frame it's part way through, then the ones before
+    let back = debugger.command(&mut frames, "rf");
+    assert!(back.starts_with("back 1 frame to cycles=30"), "{}", back);
+    assert_eq!(frames.cpu.cycles / FRAME, 3);
+    let back = debugger.command(&mut frames, "rf 2");
+    assert!(back.starts_with("back 2 frames to cycles=10"), "{}", back);
+    assert!(frames.cpu.state.memory[0x80] < counted);
+
+    // Running on again gets to the same place
+    frames.run_until(3500);
+    assert_eq!(frames.cpu.state.memory[0x80], counted);
+
+    debugger.command(&mut frames, "rf 10");
+    assert_eq!(frames.cpu.cycles, 0);
+    assert_eq!(
+        debugger.command(&mut frames, "rf"),
+        "no earlier frames to go back to\n"
+    );
+    assert_eq!(
+        debugger.command(&mut Cpu::new(Vec::new()), "rf"),
+        "no earlier frames to go back to\n"
+    );
+}
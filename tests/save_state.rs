@@ -0,0 +1,120 @@
+// Saving the whole machine and carrying on from the saved state
+use invaders_dis::assembler::assemble;
+use invaders_dis::invaders::{self, Beam, Devices};
+use invaders_dis::save_state::{Reader, Writer, MAGIC, VERSION};
+use invaders_dis::Cpu;
+
+// Keeps the shift register and sound latches busy, with interrupts on so
+// the beam's RST 1 and RST 2 land part way through
+const PROGRAM: &str = "
+        JMP     start
+        ORG     8
+        INR     E
+        EI
+        RET
+        ORG     $10
+        INR     D
+        EI
+        RET
+start:  LXI     SP,$2400
+        MVI     A,3
+        OUT     2
+        EI
+loop:   INR     B
+        MOV     A,B
+        OUT     4
+        OUT     3
+        IN      3
+        STA     $2400
+        JMP     loop
+";
+
+fn machine() -> (Cpu, Devices, Beam) {
+    let (cpu, devices) = invaders::new_cpu(assemble(PROGRAM).unwrap().bytes);
+    (cpu, devices, Beam::default())
+}
+
+// Everything that should survive a save and load, as the saved bytes
+fn run_frames(cpu: &mut Cpu, devices: &Devices, beam: &mut Beam, frames: usize) -> Vec<u8> {
+    for _ in 0..frames {
+        assert!(beam.run_frame(cpu, |_, _| {}));
+    }
+    invaders::save_state(cpu, devices, beam)
+}
+
+#[test]
+fn carries_on_the_same() {
+    let (mut cpu, devices, mut beam) = machine();
+    // Stop part way through a frame, between the two interrupts
+    beam.run_until(&mut cpu, invaders::CYCLES_PER_FRAME * 5 / 2, |_, _| {});
+    let saved = invaders::save_state(&cpu, &devices, &beam);
+    let later = run_frames(&mut cpu, &devices, &mut beam, 3);
+
+    // A fresh machine picks up where the first left off
+    let (mut other, other_devices, mut other_beam) = machine();
+    invaders::load_state(&saved, &mut other, &other_devices, &mut other_beam).unwrap();
+    assert_eq!(
+        invaders::save_state(&other, &other_devices, &other_beam),
+        saved
+    );
+    assert_eq!(
+        run_frames(&mut other, &other_devices, &mut other_beam, 3),
+        later
+    );
+    assert!(other.state.memory[0x2400] != 0);
+    assert_eq!(other_devices.shift_register.borrow().shift_offset, 3);
+}
+
+#[test]
+fn bad_states_change_nothing() {
+    let (mut cpu, devices, mut beam) = machine();
+    let saved = run_frames(&mut cpu, &devices, &mut beam, 1);
+    let (mut other, other_devices, mut other_beam) = machine();
+    let before = invaders::save_state(&other, &other_devices, &other_beam);
+    let mut load = |bytes: &[u8]| {
+        let result = invaders::load_state(bytes, &mut other, &other_devices, &mut other_beam);
+        assert_eq!(
+            invaders::save_state(&other, &other_devices, &other_beam),
+            before
+        );
+        result.unwrap_err()
+    };
+
+    assert_eq!(load(b"invaders"), "not a save state");
+    assert_eq!(load(&saved[..saved.len() - 1]), "save state ends early");
+    let mut longer = saved.clone();
+    longer.push(0);
+    assert_eq!(load(&longer), "save state has data left over");
+
+    let mut newer = saved.clone();
+    newer[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert_eq!(
+        load(&newer),
+        format!("save state version {} isn't supported", VERSION + 1)
+    );
+
+    let mut writer = Writer::new("cpm");
+    cpu.save(&mut writer);
+    assert_eq!(load(&writer.bytes), "save state is for cpm, not invaders");
+}
+
+#[test]
+fn cpu_alone() {
+    let mut cpu = Cpu::new(assemble(PROGRAM).unwrap().bytes);
+    cpu.state.pending_interrupt = Some([0xcd, 0x34, 0x12]);
+    cpu.state.a = 0x42;
+    cpu.state.condition.set_psw(0xd7);
+    cpu.cycles = 1234;
+    let mut writer = Writer::new("test");
+    cpu.save(&mut writer);
+
+    let mut other = Cpu::new(Vec::new());
+    let mut reader = Reader::new(&writer.bytes, "test").unwrap();
+    other.load(&mut reader).unwrap();
+    reader.finish().unwrap();
+    assert_eq!(other.state.pending_interrupt, Some([0xcd, 0x34, 0x12]));
+    assert_eq!(other.state.a, 0x42);
+    assert_eq!(other.state.condition.to_psw(), 0xd7);
+    assert_eq!(other.state.memory, cpu.state.memory);
+    assert_eq!(other.cycles, 1234);
+}
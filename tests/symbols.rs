@@ -0,0 +1,79 @@
+// Symbol files and the listings that use them
+use invaders_dis::assembler::assemble;
+use invaders_dis::listing::{Listing, ENTRY_POINTS};
+use invaders_dis::symbols::Symbols;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn both_formats() {
+    let plain = Symbols::parse("; Space Invaders\n0000 reset\n$18d4 init\n0x20c0 alien_table\n").unwrap();
+    let toml = Symbols::parse(
+        "[symbols]\nreset = 0x0000\n\"init\" = 0x18d4 # after reset\nalien_table = 8384\n",
+    )
+    .unwrap();
+    assert_eq!(plain, toml);
+    assert_eq!(plain.name(0x18d4), Some("init"));
+    assert_eq!(plain.address("alien_table"), Some(0x20c0));
+    assert_eq!(plain.describe(0x18d7), "init+3");
+
+    // Writing them out reads back the same
+    assert_eq!(Symbols::parse(&plain.to_string()).unwrap(), plain);
+}
+
+#[test]
+fn bad_lines() {
+    assert_eq!(Symbols::parse("0000 reset\nzz12 oops").unwrap_err(), "line 2: bad address");
+    assert!(Symbols::parse("0000 1st").is_err());
+    for register in ["a", "M", "sp", "Psw"].iter() {
+        assert_eq!(
+            Symbols::parse(&format!("0000 {}", register)).unwrap_err(),
+            format!("line 1: {} isn't a usable name", register)
+        );
+    }
+}
+
+#[test]
+fn named_listing_reassembles() {
+    let rom = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("invaders")).unwrap();
+    let mut listing = Listing::new(&rom, 0, &ENTRY_POINTS);
+
+    // Exported labels name the same addresses
+    let exported = Symbols::parse(&listing.labels.to_string()).unwrap();
+    assert_eq!(exported, listing.labels);
+
+    listing.add_symbols(&Symbols::parse("18d4 init\n20c0 alien_table\n").unwrap());
+    let source = listing.to_string();
+    assert!(source.contains("alien_table EQU     $20c0"));
+    assert!(source.contains("JMP     init"));
+    assert!(source.contains("LXI     H,alien_table"));
+    assert!(assemble(&source).unwrap().bytes == rom);
+}
+
+#[test]
+fn names_the_assembler_would_misread() {
+    for name in ["end", "nop", "db", "Equ"].iter() {
+        assert_eq!(
+            Symbols::parse(&format!("20c0 {}", name)).unwrap_err(),
+            format!("line 1: {} isn't a usable name", name)
+        );
+    }
+    // Labels ignore case in the assembler, so names have to here too
+    assert_eq!(
+        Symbols::parse("18d4 init\n18d7 Init").unwrap_err(),
+        "line 2: Init is already used"
+    );
+    let mut symbols = Symbols::parse("18d4 init\n").unwrap();
+    symbols.insert(0x18d7, "Init");
+    assert_eq!(symbols.address("init"), None);
+    assert_eq!(symbols.address("Init"), Some(0x18d7));
+
+    // Close to the reserved words is fine, and still reassembles
+    let rom = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("invaders")).unwrap();
+    let mut listing = Listing::new(&rom, 0, &ENTRY_POINTS);
+    listing.add_symbols(&Symbols::parse("20c0 end_\n18d4 nops\n").unwrap());
+    listing.add_symbols(&symbols);
+    let source = listing.to_string();
+    assert!(source.contains("end_    EQU     $20c0"), "{}", source);
+    assert!(assemble(&source).unwrap().bytes == rom);
+}
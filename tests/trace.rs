@@ -0,0 +1,170 @@
+// Traces written by the cpu, and reading them back in
+use invaders_dis::assembler::assemble;
+use invaders_dis::symbols::Symbols;
+use invaders_dis::trace::{self, Format, Record, Tracer};
+use invaders_dis::Cpu;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+const PROGRAM: &str = "
+        LXI     SP,$100
+        MVI     B,3
+loop:   DCR     B
+        JNZ     loop
+        CALL    sub
+        HLT
+sub:    XRA     A
+        RET
+";
+
+// Somewhere to write a trace that the test can still read afterwards
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Runs the program to its HLT with `tracer`, returning what it wrote
+fn run(format: Format, setup: impl FnOnce(&mut Tracer)) -> Vec<u8> {
+    let output = Shared::default();
+    let mut tracer = Tracer::new(Box::new(output.clone()), format);
+    setup(&mut tracer);
+    let mut cpu = Cpu::new(assemble(PROGRAM).unwrap().bytes);
+    cpu.tracer = Some(tracer);
+    while !cpu.halted() {
+        cpu.step();
+    }
+    let bytes = output.0.borrow().clone();
+    bytes
+}
+
+#[test]
+fn both_formats_read_back() {
+    let text = run(Format::Text, |_| {});
+    let binary = run(Format::Binary, |_| {});
+    let records = trace::read(&text).unwrap();
+    assert_eq!(trace::read(&binary).unwrap(), records);
+    assert_eq!(
+        binary.len(),
+        trace::MAGIC.len() + records.len() * trace::RECORD_SIZE
+    );
+
+    // Every instruction up to and including the HLT
+    assert_eq!(records.len(), 12);
+    let first = String::from_utf8(text).unwrap();
+    assert_eq!(
+        first.lines().nth(2).unwrap(),
+        "0005  05        DCR B            AF=0002 BC=0300 DE=0000 HL=0000 SP=0100 ----- CYC=17"
+    );
+    let last = records.last().unwrap();
+    assert_eq!(last.instruction().mnemonic, "HLT");
+    assert_eq!(last.af(), 0x0046);
+}
+
+#[test]
+fn filters_and_ring() {
+    // Just the loop
+    let text = run(Format::Text, |tracer| {
+        tracer.addresses = Some(0x0005..=0x0006)
+    });
+    let records = trace::read(&text).unwrap();
+    let loop_pcs: Vec<u16> = records.iter().map(|record| record.pc).collect();
+    assert_eq!(loop_pcs, [5, 6, 5, 6, 5, 6]);
+
+    // Only the last few, written out when the cpu halts
+    let text = run(Format::Text, |tracer| tracer.ring_size = Some(3));
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.starts_with("; last 3 instructions before HLT at 000c\n"));
+    let pcs: Vec<u16> = trace::read(text.as_bytes())
+        .unwrap()
+        .iter()
+        .map(|record| record.pc)
+        .collect();
+    assert_eq!(pcs, [0x0d, 0x0e, 0x0c]);
+}
+
+#[test]
+fn names_from_symbols() {
+    let program = assemble(PROGRAM).unwrap();
+    let mut symbols = Symbols::new();
+    for (name, address) in &program.symbols {
+        symbols.insert(*address, name);
+    }
+    let text = run(Format::Text, |tracer| tracer.symbols = symbols);
+    let text = String::from_utf8(text).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(
+        lines[3],
+        "0006  c2 05 00  JNZ loop         AF=0012 BC=0200 DE=0000 HL=0000 SP=0100 --A-- CYC=22  ; loop+1"
+    );
+    assert!(lines[8].contains("CALL sub"));
+    assert!(lines[0].ends_with("CYC=0"));
+
+    // The names don't get in the way of reading it back
+    let plain = run(Format::Text, |_| {});
+    assert_eq!(
+        trace::read(text.as_bytes()).unwrap(),
+        trace::read(&plain).unwrap()
+    );
+}
+
+#[test]
+fn names_that_look_like_bytes() {
+    let symbols = Symbols::parse("0010 ab\n0020 fe\n").unwrap();
+    // NOP at ab, MVI A,$01 at fe
+    for (pc, bytes) in [(0x10, [0x00, 0, 0]), (0x20, [0x3e, 0x01, 0])].iter() {
+        let record = Record {
+            pc: *pc,
+            bytes: *bytes,
+            cycles: 7,
+            ..Default::default()
+        };
+        let line = record.with_symbols(&symbols);
+        assert!(
+            line.contains(&format!("; {}", symbols.describe(*pc))),
+            "{}",
+            line
+        );
+        assert_eq!(Record::parse(&line), Some(record));
+    }
+}
+
+#[test]
+fn interrupts_taken_are_left_out() {
+    let program = assemble(
+        "
+        LXI     SP,$100
+        EI
+        NOP
+        NOP
+        ORG     $10
+        XRA     A
+        HLT
+",
+    )
+    .unwrap();
+    let output = Shared::default();
+    let mut cpu = Cpu::new(program.bytes);
+    cpu.tracer = Some(Tracer::new(Box::new(output.clone()), Format::Text));
+    for _ in 0..3 {
+        cpu.step();
+    }
+    cpu.state.generate_interrupt(2);
+    while !cpu.halted() {
+        cpu.step();
+    }
+
+    // The handler follows the NOP it interrupted, nothing ran at 0005
+    let records = trace::read(&output.0.borrow()).unwrap();
+    let pcs: Vec<u16> = records.iter().map(|record| record.pc).collect();
+    assert_eq!(pcs, [0x00, 0x03, 0x04, 0x10, 0x11]);
+    assert_eq!(records[3].sp, 0x00fe);
+}
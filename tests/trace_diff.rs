@@ -0,0 +1,95 @@
+// Finding where our trace and another emulator's log part ways
+use invaders_dis::trace::Record;
+use invaders_dis::trace_diff::{compare, read_reference, report, Reference};
+
+// A short run of records, counting B down with DCR
+fn ours() -> Vec<Record> {
+    (0..8)
+        .map(|step| Record {
+            cycles: step * 5,
+            pc: 0x0100 + step as u16,
+            bytes: [0x05, 0, 0],
+            a: 0x12,
+            flags: if step == 7 { 0x46 } else { 0x02 },
+            bc: (7 - step as u16) << 8,
+            sp: 0x2400,
+            ..Default::default()
+        })
+        .collect()
+}
+
+// The same run as another emulator would log it
+fn reference(records: &[Record]) -> String {
+    records
+        .iter()
+        .map(|record| {
+            format!(
+                "PC: {:04X}, AF: {:04X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}\n",
+                record.pc,
+                record.af(),
+                record.bc,
+                record.de,
+                record.hl,
+                record.sp
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn reads_common_formats() {
+    let separate =
+        Reference::parse("A:12 F:46 B:01 C:02 D:00 E:00 H:ab L:cd SP:2400 PC:0100").unwrap();
+    let pairs =
+        Reference::parse("PC=0x0100 AF=0x1246 BC=0x0102 DE=0x0000 HL=0xabcd SP=0x2400").unwrap();
+    assert_eq!(separate.hl, Some(0xabcd));
+    assert_eq!(
+        Reference {
+            line: String::new(),
+            ..separate
+        },
+        Reference {
+            line: String::new(),
+            ..pairs
+        }
+    );
+
+    // Registers it leaves out aren't compared
+    let sparse = Reference::parse("pc: 0100 sp: 2400").unwrap();
+    assert_eq!(sparse.a, None);
+    assert!(Reference::parse("A:12 F:46").is_none());
+}
+
+#[test]
+fn matching_logs() {
+    let ours = ours();
+    assert_eq!(compare(&ours, &read_reference(&reference(&ours))), None);
+}
+
+#[test]
+fn names_the_flag_that_differs() {
+    let ours = ours();
+    let mut wrong = ours.clone();
+    // Z is fine, the reference says parity is clear
+    wrong[7].flags = 0x42;
+    let theirs = read_reference(&reference(&wrong));
+
+    let divergence = compare(&ours, &theirs).unwrap();
+    assert_eq!(divergence.index, 7);
+    assert_eq!(divergence.register, "flag P");
+    let text = report(&ours, &theirs, &divergence);
+    assert!(text.starts_with(
+        "first difference at instruction 7\nafter 0106 DCR B flag P: ours 1, reference 0\n"
+    ));
+    // The records before it from both logs, the difference marked
+    assert!(text.contains("\n> 0107  05        DCR B "));
+    assert!(text.ends_with("> PC: 0107, AF: 1242, BC: 0000, DE: 0000, HL: 0000, SP: 2400\n"));
+}
+
+#[test]
+fn logs_of_different_lengths() {
+    let ours = ours();
+    let theirs = read_reference(&reference(&ours[..5]));
+    let divergence = compare(&ours, &theirs).unwrap();
+    assert_eq!((divergence.index, divergence.register.as_str()), (5, "end"));
+}
@@ -0,0 +1,62 @@
+// The undocumented opcodes run as the instructions they alias on real 8080s
+use invaders_dis::cpu::get_cycles;
+use invaders_dis::disassembler::decode;
+use invaders_dis::Cpu;
+
+// Runs a program loaded at 0 with the stack at the top of memory
+fn cpu(program: &[u8]) -> Cpu {
+    let mut cpu = Cpu::new(program.to_vec());
+    cpu.state.sp = 0xf000;
+    cpu
+}
+
+#[test]
+fn nop_aliases() {
+    for opcode in [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38].iter() {
+        let mut cpu = cpu(&[*opcode]);
+        cpu.state.a = 0x42;
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.state.pc, 1);
+        assert_eq!(cpu.state.a, 0x42);
+    }
+}
+
+#[test]
+fn jmp_alias() {
+    let mut cpu = cpu(&[0xcb, 0x34, 0x12]);
+    assert_eq!(cpu.step(), get_cycles(0xc3) as u32);
+    assert_eq!(cpu.state.pc, 0x1234);
+}
+
+#[test]
+fn call_and_ret_aliases() {
+    for opcode in [0xdd, 0xed, 0xfd].iter() {
+        let mut program = vec![0; 0x20];
+        program[0..3].copy_from_slice(&[*opcode, 0x10, 0x00]);
+        program[0x10] = 0xd9;
+        let mut cpu = cpu(&program);
+
+        assert_eq!(cpu.step(), get_cycles(0xcd) as u32);
+        assert_eq!(cpu.state.pc, 0x0010);
+        assert_eq!(cpu.state.sp, 0xeffe);
+
+        assert_eq!(cpu.step(), get_cycles(0xc9) as u32);
+        assert_eq!(cpu.state.pc, 0x0003);
+        assert_eq!(cpu.state.sp, 0xf000);
+    }
+}
+
+#[test]
+fn listed_with_a_star() {
+    for opcode in [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38].iter() {
+        assert_eq!(decode(&[*opcode], 0).to_string(), "*NOP");
+    }
+    assert_eq!(decode(&[0xcb, 0x34, 0x12], 0).to_string(), "*JMP $1234");
+    for opcode in [0xdd, 0xed, 0xfd].iter() {
+        assert_eq!(decode(&[*opcode, 0x10, 0x00], 0).to_string(), "*CALL $0010");
+    }
+    assert_eq!(decode(&[0xd9], 0).to_string(), "*RET");
+
+    // The documented ones they alias have no star
+    assert_eq!(decode(&[0xc3, 0x34, 0x12], 0).to_string(), "JMP $1234");
+}